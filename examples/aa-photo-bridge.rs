@@ -1,9 +1,16 @@
+use aa_photo_bridge::i2a::video::{
+    AudioChannels, AudioOptions, HdrPolicy, KeyFrame, RateControl, VideoEncodeOptions, VideoRotation, VideoTrim,
+};
+use aa_photo_bridge::i2a::FileTimeMode;
+use aa_photo_bridge::utils::{ExifTool, MetadataBackend, MetadataPolicy, TimeShift};
 use anyhow::{Context, Result};
 use clap::Parser;
 use rayon::prelude::*;
 use std::{
     collections::HashSet,
     path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
 };
 use tracing::*;
 use tracing_indicatif::span_ext::IndicatifSpanExt;
@@ -32,7 +39,7 @@ pub struct Args {
     /// Overwrite output file if already exist.
     pub overwrite_existing: bool,
 
-    /// Add some suffix in output file to avoid filename collision. For example, 
+    /// Add some suffix in output file to avoid filename collision. For example,
     /// "--output-suffix _merge"
     #[clap(long)]
     pub output_suffix: Option<String>,
@@ -53,6 +60,106 @@ pub struct Args {
     /// Gainmap quality. Default: 85
     pub gainmap_quality: i32,
 
+    #[clap(long, value_name = "SECONDS", value_parser = parse_seconds, requires = "trim_after")]
+    /// Trim the video to keep this many seconds before the key photo. Use with --trim-after.
+    pub trim_before: Option<Duration>,
+
+    #[clap(long, value_name = "SECONDS", value_parser = parse_seconds, requires = "trim_before")]
+    /// Trim the video to keep this many seconds after the key photo. Use with --trim-before.
+    pub trim_after: Option<Duration>,
+
+    #[clap(long, value_name = "SECONDS", value_parser = parse_seconds, conflicts_with_all = ["trim_before", "trim_after"])]
+    /// Trim the video to at most this many seconds around the key photo.
+    pub max_video_duration: Option<Duration>,
+
+    #[clap(long)]
+    /// Start the trimmed video exactly, re-encoding it if needed. By default it starts at the keyframe before.
+    pub exact_trim: bool,
+
+    #[clap(long)]
+    /// Re-encode the video to H.264, for older Android phones that cannot play HEVC.
+    pub transcode_video: bool,
+
+    #[clap(long, requires = "transcode_video")]
+    /// Video encoder name, e.g. "libx264". Default: the first available H.264 encoder
+    pub video_encoder: Option<String>,
+
+    #[clap(long, requires = "transcode_video", conflicts_with = "video_bitrate")]
+    /// Video constant rate factor. Default: 23
+    pub video_crf: Option<u8>,
+
+    #[clap(long, requires = "transcode_video")]
+    /// Video bit rate, in bits per second.
+    pub video_bitrate: Option<i64>,
+
+    #[clap(long, value_name = "PIXELS", requires = "transcode_video")]
+    /// Scale down the video so that the longer edge is at most this many pixels.
+    pub video_max_size: Option<u32>,
+
+    #[clap(long)]
+    /// Remove the audio track from the motion photos.
+    pub strip_audio: bool,
+
+    #[clap(long)]
+    /// Always remux the video into a clean MP4 with only video and audio tracks, even if the MOV could be embedded as is.
+    pub remux_video: bool,
+
+    #[clap(long, value_enum, default_value = "keep")]
+    /// What to do with rotated videos. Keep: keep the rotation flag; bake: rotate the pixels by re-encoding.
+    pub video_rotation: Rotation,
+
+    #[clap(long, value_enum, default_value = "keep")]
+    /// What to do with HDR (Dolby Vision / HLG) videos. Strip-dolby-vision: keep the HLG base layer; tone-map: re-encode to SDR.
+    pub video_hdr: Hdr,
+
+    #[clap(long, default_value = "aac")]
+    /// Audio encoder name, e.g. "aac" or "libopus", used for audio codecs that are not passed through.
+    pub audio_encoder: String,
+
+    #[clap(long, default_value_t = 128 << 10)]
+    /// Audio bit rate, in bits per second.
+    pub audio_bitrate: i64,
+
+    #[clap(long, value_name = "HZ")]
+    /// Resample the transcoded audio to this rate. Default: same as the input
+    pub audio_sample_rate: Option<i32>,
+
+    #[clap(long, value_enum, default_value = "keep")]
+    /// Channel layout of the transcoded audio. Keep: same as the input.
+    pub audio_channels: Channels,
+
+    #[clap(long, value_delimiter = ',', default_value = "aac,ac3")]
+    /// Audio codecs that are copied untouched instead of transcoded.
+    pub audio_passthrough: Vec<String>,
+
+    #[clap(long)]
+    /// Also convert videos without an image, taking the key photo from the video.
+    pub still_from_video: bool,
+
+    #[clap(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    /// Use the video frame at this time as the key photo, instead of the image.
+    pub key_frame_time: Option<Duration>,
+
+    #[clap(long, value_name = "N", conflicts_with = "key_frame_time")]
+    /// Use the n-th video frame (from 0) as the key photo, instead of the image.
+    pub key_frame_index: Option<usize>,
+
+    #[clap(long, value_enum, default_value = "keep-all")]
+    /// Which metadata to keep in the image and the video. Strip-location: remove GPS and places; strip-device: remove MakerNotes and serial numbers; date-only: keep only the capture date.
+    pub metadata_policy: Metadata,
+
+    #[clap(long, value_name = "SECONDS", allow_hyphen_values = true)]
+    /// Move the capture dates of the image and the video by this many seconds, e.g. for a wrong device clock.
+    pub time_shift: Option<i64>,
+
+    #[clap(long, value_name = "+HH:MM", allow_hyphen_values = true, conflicts_with = "time_shift", value_parser = parse_time_zone)]
+    /// Rewrite the capture dates of the image and the video in this time zone, keeping the same instant.
+    pub time_zone: Option<TimeShift>,
+
+    #[clap(long)]
+    /// Set the output file times to the capture date in the metadata, instead of the times of the source file.
+    pub file_times_from_capture_date: bool,
+
     #[clap(long)]
     /// Strict mode: exit on multiple images / videos with same name.
     pub strict: bool,
@@ -60,6 +167,10 @@ pub struct Args {
     #[clap(short = 'v', long)]
     /// Print more detailed runtime information
     pub verbose: bool,
+
+    #[clap(skip)]
+    /// Shared by all tasks, so that they reuse the exiftool processes
+    metadata: OnceLock<Arc<dyn MetadataBackend>>,
 }
 #[derive(clap::ValueEnum, Clone, Copy, PartialEq)]
 pub enum Original {
@@ -67,6 +178,70 @@ pub enum Original {
     Delete,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Keep,
+    Bake,
+}
+impl From<Rotation> for VideoRotation {
+    fn from(rotation: Rotation) -> Self {
+        match rotation {
+            Rotation::Keep => VideoRotation::Keep,
+            Rotation::Bake => VideoRotation::Bake,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq)]
+pub enum Hdr {
+    Keep,
+    StripDolbyVision,
+    ToneMap,
+}
+impl From<Hdr> for HdrPolicy {
+    fn from(hdr: Hdr) -> Self {
+        match hdr {
+            Hdr::Keep => HdrPolicy::Keep,
+            Hdr::StripDolbyVision => HdrPolicy::StripDolbyVision,
+            Hdr::ToneMap => HdrPolicy::ToneMapSdr,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq)]
+pub enum Metadata {
+    KeepAll,
+    StripLocation,
+    StripDevice,
+    DateOnly,
+}
+impl From<Metadata> for MetadataPolicy {
+    fn from(metadata: Metadata) -> Self {
+        match metadata {
+            Metadata::KeepAll => MetadataPolicy::KeepAll,
+            Metadata::StripLocation => MetadataPolicy::StripLocation,
+            Metadata::StripDevice => MetadataPolicy::StripDevice,
+            Metadata::DateOnly => MetadataPolicy::DateOnly,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq)]
+pub enum Channels {
+    Keep,
+    Mono,
+    Stereo,
+}
+impl From<Channels> for AudioChannels {
+    fn from(channels: Channels) -> Self {
+        match channels {
+            Channels::Keep => AudioChannels::Keep,
+            Channels::Mono => AudioChannels::Mono,
+            Channels::Stereo => AudioChannels::Stereo,
+        }
+    }
+}
+
 impl Args {
     pub fn image_extensions(&self) -> HashSet<String> {
         self.image_extensions
@@ -85,6 +260,61 @@ impl Args {
             .collect()
     }
 
+    pub fn video_trim(&self) -> Option<VideoTrim> {
+        if let Some(max) = self.max_video_duration {
+            return Some(VideoTrim::MaxDuration(max));
+        }
+        match (self.trim_before, self.trim_after) {
+            (Some(before), Some(after)) => Some(VideoTrim::Around { before, after }),
+            _ => None,
+        }
+    }
+
+    pub fn time_shift(&self) -> Option<TimeShift> {
+        self.time_shift.map(TimeShift::Offset).or(self.time_zone)
+    }
+
+    pub fn metadata(&self) -> Arc<dyn MetadataBackend> {
+        let metadata = self.metadata.get_or_init(|| match self.exiftool.as_ref() {
+            Some(path) => Arc::new(ExifTool::with_path(path.clone())),
+            None => Arc::new(ExifTool::new()),
+        });
+        metadata.clone()
+    }
+
+    pub fn key_frame(&self) -> Option<KeyFrame> {
+        if let Some(time) = self.key_frame_time {
+            return Some(KeyFrame::Timestamp(i64::try_from(time.as_micros()).unwrap_or(i64::MAX)));
+        }
+        self.key_frame_index.map(KeyFrame::Index)
+    }
+
+    pub fn audio(&self) -> AudioOptions {
+        let mut options = AudioOptions::default();
+        options.encoder = self.audio_encoder.clone();
+        options.bit_rate = self.audio_bitrate;
+        options.sample_rate = self.audio_sample_rate;
+        options.channels = self.audio_channels.into();
+        options.passthrough_codecs = self.audio_passthrough.clone();
+        options
+    }
+
+    pub fn video_encode(&self) -> Option<VideoEncodeOptions> {
+        if !self.transcode_video {
+            return None;
+        }
+        let mut options = VideoEncodeOptions::default();
+        options.encoder = self.video_encoder.clone();
+        options.max_size = self.video_max_size;
+        if let Some(crf) = self.video_crf {
+            options.rate_control = RateControl::Crf(crf);
+        }
+        if let Some(bit_rate) = self.video_bitrate {
+            options.rate_control = RateControl::BitRate(bit_rate);
+        }
+        Some(options)
+    }
+
     fn visit(&self, path: &Path, tasks: &mut Vec<Task>) -> Result<()> {
         anyhow::ensure!(path.exists(), "Path does not exist: {:?}", path);
         anyhow::ensure!(path.is_dir(), "Not a directory: {:?}", path);
@@ -133,7 +363,7 @@ impl Args {
             .iter()
             .filter_map(|ext| find_ext(ext).or_else(|| find_ext(&ext.to_uppercase())))
             .collect::<Vec<_>>();
-        if found_image.is_empty() {
+        if found_image.is_empty() && !self.still_from_video {
             return Ok(());
         }
         if found_image.len() > 1 {
@@ -146,8 +376,12 @@ impl Args {
             }
             return Ok(());
         }
-        let image_path = found_image.pop().unwrap();
-        if image_path.as_os_str().eq_ignore_ascii_case(path.as_os_str()) {
+        // None: take the still image from the video
+        let image_path = found_image.pop();
+        if image_path
+            .as_ref()
+            .is_some_and(|p| p.as_os_str().eq_ignore_ascii_case(path.as_os_str()))
+        {
             return Ok(());
         }
         // anyhow::ensure!(image_path == path, "{image_path:#?} != {path:#?}");
@@ -170,6 +404,10 @@ impl Args {
             return Ok(());
         }
         let video_path = found_video.pop().unwrap();
+        if image_path.is_none() && !video_path.as_os_str().eq_ignore_ascii_case(path.as_os_str()) {
+            // visit the video only once
+            return Ok(());
+        }
 
         let mut output_path = path.with_extension("jpg");
         if let Some(suffix) = self.output_suffix.as_deref() {
@@ -178,21 +416,49 @@ impl Args {
             let suffixed_filename = format!("{stem}{suffix}.{extension}");
             output_path.set_file_name(suffixed_filename);
         }
-        tasks.push(Task {
-            image_path,
-            video_path,
-            output_path,
-            exiftool_path: self.exiftool.clone(),
-            image_quality: self.image_quality,
-            gainmap_quality: self.gainmap_quality,
-            overwrite_existing: self.overwrite_existing,
-        });
+        let mut task = Task::builder()
+            .video_path(video_path)
+            .output_path(output_path)
+            .metadata(self.metadata())
+            .image_quality(self.image_quality)
+            .gainmap_quality(self.gainmap_quality)
+            .overwrite_existing(self.overwrite_existing)
+            .video_trim(self.video_trim())
+            .exact_trim(self.exact_trim)
+            .video_encode(self.video_encode())
+            .video_rotation(self.video_rotation.into())
+            .audio(self.audio())
+            .strip_audio(self.strip_audio)
+            .always_remux(self.remux_video)
+            .video_hdr(self.video_hdr.into())
+            .key_frame(self.key_frame())
+            .metadata_policy(self.metadata_policy.into())
+            .time_shift(self.time_shift())
+            .file_times(match self.file_times_from_capture_date {
+                true => FileTimeMode::CaptureDate,
+                false => FileTimeMode::Source,
+            });
+        if let Some(image_path) = image_path {
+            task = task.image_path(image_path);
+        }
+        tasks.push(task.build()?);
         Ok(())
     }
 }
 
+/// Seconds, e.g. "1.5". Negative, NaN and infinite values are rejected.
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    let parsed = seconds.parse::<f32>().map_err(|e| format!("invalid seconds {seconds:?}: {e}"))?;
+    Duration::try_from_secs_f32(parsed).map_err(|e| format!("invalid seconds {seconds:?}: {e}"))
+}
+
+fn parse_time_zone(offset: &str) -> Result<TimeShift, String> {
+    TimeShift::time_zone(offset).ok_or_else(|| format!("invalid time zone {offset:?}, expected +HH:MM"))
+}
+
 fn run(task: Task, original: Original) -> Result<()> {
-    task.convert().with_context(|| format!("Task failed for {task:#?}"))?;
+    let report = task.convert().with_context(|| format!("Task failed for {task:#?}"))?;
+    debug!(?report, "task converted");
     if original == Original::Delete {
         task.delete_original()?;
     }
@@ -0,0 +1,183 @@
+use std::{path::PathBuf, sync::Arc};
+
+use super::{progress, video, ConvertError, ConvertRequest, FileTimeMode};
+use crate::utils::{ExifTool, MetadataBackend, MetadataPolicy, TimeShift};
+
+/// Builds a [`ConvertRequest`]. Only the output path is required.
+#[derive(Debug)]
+pub struct ConvertRequestBuilder {
+    image_path: Option<PathBuf>,
+    video_path: Option<PathBuf>,
+    output_path: Option<PathBuf>,
+    metadata: Option<Arc<dyn MetadataBackend>>,
+    overwrite_existing: bool,
+    image_quality: i32,
+    gainmap_quality: i32,
+    video_trim: Option<video::VideoTrim>,
+    exact_trim: bool,
+    video_encode: Option<video::VideoEncodeOptions>,
+    video_rotation: video::VideoRotation,
+    audio: video::AudioOptions,
+    strip_audio: bool,
+    always_remux: bool,
+    video_hdr: video::HdrPolicy,
+    key_frame: Option<video::KeyFrame>,
+    metadata_policy: MetadataPolicy,
+    time_shift: Option<TimeShift>,
+    file_times: FileTimeMode,
+    progress: progress::Progress,
+}
+
+impl Default for ConvertRequestBuilder {
+    fn default() -> Self {
+        Self {
+            image_path: None,
+            video_path: None,
+            output_path: None,
+            metadata: None,
+            overwrite_existing: false,
+            image_quality: 85,
+            gainmap_quality: 85,
+            video_trim: None,
+            exact_trim: false,
+            video_encode: None,
+            video_rotation: Default::default(),
+            audio: Default::default(),
+            strip_audio: false,
+            always_remux: false,
+            video_hdr: Default::default(),
+            key_frame: None,
+            metadata_policy: Default::default(),
+            time_shift: None,
+            file_times: Default::default(),
+            progress: Default::default(),
+        }
+    }
+}
+
+impl ConvertRequest {
+    pub fn builder() -> ConvertRequestBuilder {
+        ConvertRequestBuilder::default()
+    }
+}
+
+impl ConvertRequestBuilder {
+    /// Not set takes the key photo from the video
+    pub fn image_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.image_path = Some(path.into());
+        self
+    }
+    pub fn video_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.video_path = Some(path.into());
+        self
+    }
+    /// Must have a jpg extension
+    pub fn output_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.output_path = Some(path.into());
+        self
+    }
+    /// Default: exiftool from `PATH`, whose processes exit with the request.
+    /// Share one `Arc<ExifTool>` between requests to reuse its processes.
+    pub fn metadata(mut self, metadata: Arc<dyn MetadataBackend>) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+    pub fn overwrite_existing(mut self, overwrite_existing: bool) -> Self {
+        self.overwrite_existing = overwrite_existing;
+        self
+    }
+    /// [0, 100]. Default: 85
+    pub fn image_quality(mut self, quality: i32) -> Self {
+        self.image_quality = quality;
+        self
+    }
+    /// [0, 100]. Default: 85
+    pub fn gainmap_quality(mut self, quality: i32) -> Self {
+        self.gainmap_quality = quality;
+        self
+    }
+    pub fn video_trim(mut self, trim: impl Into<Option<video::VideoTrim>>) -> Self {
+        self.video_trim = trim.into();
+        self
+    }
+    /// Start the trimmed video exactly, re-encoding it if the start is not on a keyframe.
+    /// Default: false, the video is copied from the keyframe at or before the start.
+    pub fn exact_trim(mut self, exact_trim: bool) -> Self {
+        self.exact_trim = exact_trim;
+        self
+    }
+    pub fn video_encode(mut self, options: impl Into<Option<video::VideoEncodeOptions>>) -> Self {
+        self.video_encode = options.into();
+        self
+    }
+    pub fn video_rotation(mut self, rotation: video::VideoRotation) -> Self {
+        self.video_rotation = rotation;
+        self
+    }
+    pub fn audio(mut self, audio: video::AudioOptions) -> Self {
+        self.audio = audio;
+        self
+    }
+    pub fn strip_audio(mut self, strip_audio: bool) -> Self {
+        self.strip_audio = strip_audio;
+        self
+    }
+    pub fn always_remux(mut self, always_remux: bool) -> Self {
+        self.always_remux = always_remux;
+        self
+    }
+    pub fn video_hdr(mut self, hdr: video::HdrPolicy) -> Self {
+        self.video_hdr = hdr;
+        self
+    }
+    pub fn key_frame(mut self, key_frame: impl Into<Option<video::KeyFrame>>) -> Self {
+        self.key_frame = key_frame.into();
+        self
+    }
+    pub fn metadata_policy(mut self, policy: MetadataPolicy) -> Self {
+        self.metadata_policy = policy;
+        self
+    }
+    pub fn time_shift(mut self, shift: impl Into<Option<TimeShift>>) -> Self {
+        self.time_shift = shift.into();
+        self
+    }
+    pub fn file_times(mut self, file_times: FileTimeMode) -> Self {
+        self.file_times = file_times;
+        self
+    }
+    pub fn progress(mut self, progress: progress::Progress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Check the options. The files are checked when the request is converted.
+    pub fn build(self) -> Result<ConvertRequest, ConvertError> {
+        let request = ConvertRequest {
+            image_path: self.image_path,
+            video_path: self.video_path,
+            output_path: self.output_path.ok_or(ConvertError::MissingOption { option: "output_path" })?,
+            metadata: self.metadata.unwrap_or_else(|| Arc::new(ExifTool::new())),
+            overwrite_existing: self.overwrite_existing,
+            image_quality: self.image_quality,
+            gainmap_quality: self.gainmap_quality,
+            video_trim: self.video_trim,
+            exact_trim: self.exact_trim,
+            video_encode: self.video_encode,
+            video_rotation: self.video_rotation,
+            audio: self.audio,
+            strip_audio: self.strip_audio,
+            always_remux: self.always_remux,
+            video_hdr: self.video_hdr,
+            key_frame: self.key_frame,
+            metadata_policy: self.metadata_policy,
+            time_shift: self.time_shift,
+            file_times: self.file_times,
+            progress: self.progress,
+            image_data: None,
+            video_data: None,
+        };
+        request.check_options()?;
+        Ok(request)
+    }
+}
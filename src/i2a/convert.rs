@@ -2,61 +2,99 @@ use anyhow::{Context, Result};
 use libheif_rs::{HeifContext, LibHeif};
 use std::{ffi::OsStr, path::Path};
 
-use super::ConvertRequest;
+use super::{progress::Stage, report::Recorder, video, ConvertError, ConvertRequest, KeyPhoto};
+use crate::utils::PhotoMetadata;
 
 impl ConvertRequest {
     pub(crate) fn image_extension(&self) -> Result<&OsStr> {
-        self.image_path.extension().context("No extension found for image path")
+        self.image_path
+            .as_deref()
+            .context("No image path")?
+            .extension()
+            .context("No extension found for image path")
     }
 
-    /// convert heic to jpg, save to output_path
+    /// convert heic to jpg
     ///
     /// # Reference
     /// 1. https://developer.apple.com/documentation/appkit/applying-apple-hdr-effect-to-your-photos
     #[tracing::instrument(skip_all)]
-    pub(crate) fn convert_heic_to_jpg(&self) -> anyhow::Result<()> {
+    pub(crate) fn convert_heic_to_jpg(&self, report: &mut Recorder, image_path: &Path) -> anyhow::Result<(Vec<u8>, PhotoMetadata)> {
         anyhow::ensure!(self.is_input_heic()?, "Not a heic file");
-        self.do_convert_heic_to_jpg(&self.image_path, &self.output_path)
-            .with_context(|| format!("convert heic to jpeg failed: {}", self.image_path.display()))?;
-        debug!(size=%self.output_path.metadata()?.len(), "heic converted to jpg");
-        // sync metadata
-        self.exif_tool()
-            .copy_meta(&self.image_path, &self.output_path)
-            .context("write exiftool failed")?;
-        trace!("heic convert: jpg exif copied");
-        Ok(())
+        let (jpg, metadata) = self
+            .do_convert_heic_to_jpg(report, image_path)
+            .with_context(|| format!("convert heic to jpeg failed: {}", image_path.display()))?;
+        debug!(size = jpg.len(), "heic converted to jpg");
+        Ok((jpg, metadata))
+    }
+
+    /// Take the key photo from the video and encode it as jpg
+    #[tracing::instrument(skip_all)]
+    pub(crate) fn convert_video_frame_to_jpg(
+        &self,
+        report: &mut Recorder,
+        key_frame: video::KeyFrame,
+    ) -> anyhow::Result<(Vec<u8>, KeyPhoto)> {
+        self.stage(report, Stage::Decode)?;
+        let frame = video::VideoFrameRequest {
+            input: self.video_input()?,
+            frame: key_frame,
+        }
+        .execute()?;
+        let key_photo = KeyPhoto {
+            timestamp_us: Some(frame.timestamp_us),
+            frame_size: Some((frame.width, frame.height)),
+            metadata: None,
+            shift_seconds: None,
+        };
+        let image = turbojpeg::YuvImage {
+            pixels: frame.yuv420p,
+            width: frame.width,
+            align: 1,
+            height: frame.height,
+            subsamp: turbojpeg::Subsamp::Sub2x2,
+        };
+        self.stage(report, Stage::BaseEncode)?;
+        let mut comp = turbojpeg::Compressor::new()?;
+        comp.set_subsamp(turbojpeg::Subsamp::Sub2x2)?;
+        comp.set_quality(self.image_quality)?;
+        comp.set_optimize(false)?;
+        let jpg = comp.compress_yuv_to_owned(image.as_deref())?;
+        report.update(|report| report.base_image_bytes = jpg.len() as u64);
+        debug!(size = jpg.len(), timestamp_us = frame.timestamp_us, "video frame encoded as jpg");
+        Ok((jpg.to_vec(), key_photo))
+    }
+
+    fn unsupported_image(&self, reason: String) -> anyhow::Error {
+        let path = self.source_path().to_path_buf();
+        ConvertError::UnsupportedImage { path, reason }.into()
+    }
+
+    fn hdr_metadata(&self, reason: String) -> anyhow::Error {
+        let path = self.source_path().to_path_buf();
+        ConvertError::HdrMetadata { path, reason }.into()
     }
 
     /// Return Some(headroom) if HDR heic, None if not HDR heic
-    fn get_apple_headroom_from_exif(&self, path: &Path) -> anyhow::Result<Option<f32>> {
+    fn get_apple_headroom_from_exif(metadata: &PhotoMetadata) -> anyhow::Result<Option<f32>> {
         // credit: https://github.com/johncf/apple-hdr-heic/blob/e64716c29abc91a3b40543d7c47fb0f526608982/src/apple_hdr_heic/metadata.py#L17
         // reference: https://developer.apple.com/documentation/appkit/images_and_pdf/applying_apple_hdr_effect_to_your_photos
         //            https://github.com/exiftool/exiftool/blob/405674e0/lib/Image/ExifTool/Apple.pm
         // verify HDRGainMapVersion key
-        let hdr_version = self.exif_tool().get_value(path, "xmp:HDRGainMapVersion")?;
-        let Some(hdr_version) = hdr_version else {
+        let Some(hdr_version) = metadata.hdr_gain_map_version else {
             debug!("no HDRGainMapVersion, not HDR heic");
             return Ok(None);
         };
         trace!("detected Apple HDRGainMapVersion = {hdr_version}");
-        if let Some(headroom) = self.exif_tool().get_value(path, "xmp:HDRGainMapHeadroom")? {
+        if let Some(headroom) = metadata.hdr_gain_map_headroom {
             trace!(%headroom, "got xmp:HDRGainMapHeadroom");
-            let headroom = headroom.parse::<f32>().context("Invalid HDRGainMapHeadroom value")?;
             return Ok(Some(headroom));
         }
         // get markers
-        let marker33 = self
-            .exif_tool()
-            .get_value(path, "MakerNotes:HDRHeadroom")?
-            .context("No Markers MakerNotes:HDRHeadroom found, not HDR heic")?
-            .parse::<f32>()
-            .context("Invalid Marker33 value")?;
-        let marker48 = self
-            .exif_tool()
-            .get_value(path, "MakerNotes:HDRGain")?
-            .context("No Markers MakerNotes:HDRGain found, not HDR heic")?
-            .parse::<f32>()
-            .context("Invalid Marker48 value")?;
+        let marker33 = metadata
+            .hdr_headroom
+            .context("No Markers MakerNotes:HDRHeadroom found, not HDR heic")?;
+        let marker48 = metadata.hdr_gain.context("No Markers MakerNotes:HDRGain found, not HDR heic")?;
         let stops = if marker33 < 1.0 {
             if marker48 <= 0.01 {
                 -20.0 * marker48 + 1.8
@@ -162,9 +200,13 @@ impl ConvertRequest {
         let (w, h) = (image.width() as usize, image.height() as usize);
 
         let colorspace = image.color_space().context("no color space")?;
-        anyhow::ensure!(colorspace == libheif_rs::ColorSpace::YCbCr(libheif_rs::Chroma::C420));
+        if colorspace != libheif_rs::ColorSpace::YCbCr(libheif_rs::Chroma::C420) {
+            return Err(self.unsupported_image(format!("color space {colorspace:?}, not YCbCr 4:2:0")));
+        }
         let y_bits = image.bits_per_pixel(libheif_rs::Channel::Y).context("no bits per pixel")?;
-        anyhow::ensure!(y_bits == 8);
+        if y_bits != 8 {
+            return Err(self.unsupported_image(format!("{y_bits} bits per pixel, not 8")));
+        }
         let planes = image.planes();
         let y = planes.y.context("no y plane")?;
         let cb = planes.cb.context("no cb")?;
@@ -213,17 +255,24 @@ impl ConvertRequest {
         Ok(jpg)
     }
 
-    fn do_convert_heic_to_jpg(&self, src: &Path, output: &Path) -> anyhow::Result<()> {
-        let profile = self.exif_tool().get_value(src, "ProfileDescription")?;
-        if let Some(profile) = profile.as_ref() {
-            anyhow::ensure!(profile.starts_with("Display P3"));
+    /// Returns the jpg, and the metadata of the heic that it was made with
+    fn do_convert_heic_to_jpg(&self, report: &mut Recorder, src: &Path) -> anyhow::Result<(Vec<u8>, PhotoMetadata)> {
+        let metadata = self.metadata.read(src).map_err(ConvertError::metadata(src))?;
+        let profile = metadata.profile_description.as_ref();
+        if let Some(profile) = profile.filter(|profile| !profile.starts_with("Display P3")) {
+            return Err(self.unsupported_image(format!("color profile {profile}, not Display P3")));
         }
         trace!(?profile, "ProfileDescription");
         // open image and decode
+        self.stage(report, Stage::Decode)?;
         let span = info_span!("decode heic");
         let guard = span.enter();
         let lib_heif = LibHeif::new();
-        let ctx = HeifContext::read_from_file(src.to_str().unwrap()).context("libheif: read heic failed")?;
+        let ctx = match self.image_data.as_ref() {
+            Some(data) => HeifContext::read_from_bytes(data),
+            None => HeifContext::read_from_file(src.to_str().context("image path is not UTF-8")?),
+        }
+        .context("libheif: read heic failed")?;
         let handle = ctx.primary_image_handle().context("libheif: get image handle failed")?;
         let (width, height) = (handle.width(), handle.height());
         debug!(width, height, "heic-convert: heic file opened, decoding");
@@ -235,17 +284,24 @@ impl ConvertRequest {
         debug!("primary image decoded, {width} x {height}");
         drop(guard);
 
+        self.stage(report, Stage::BaseEncode)?;
         let mut primary_image = info_span!("encoding sdr to jpg").in_scope(|| self.convert_primary_image_to_jpg(&primary_image))?;
 
         // check if apple HDR
-        let apple_headroom = self.get_apple_headroom_from_exif(src)?;
+        let apple_headroom = Self::get_apple_headroom_from_exif(&metadata).map_err(|e| self.hdr_metadata(e.to_string()))?;
         debug!(?apple_headroom, "apple headroom");
+        report.update(|report| {
+            report.hdr = apple_headroom.is_some();
+            report.headroom = apple_headroom;
+            report.base_image_bytes = primary_image.len() as u64;
+        });
         let Some(apple_headroom) = apple_headroom else {
             debug!("not apple HDR, skip HDR");
-            std::fs::write(output, &primary_image)?;
-            return Ok(());
+            return Ok((primary_image.to_vec(), metadata));
         };
-        anyhow::ensure!(profile.is_some(), "Apple headroom found, but ProfileDescription not found in exif");
+        if profile.is_none() {
+            return Err(self.hdr_metadata("Apple headroom found, but ProfileDescription not found in exif".to_string()));
+        }
 
         // write ultra HDR image
         let mut encoder = libultrahdr_rs::Encoder::new();
@@ -257,10 +313,16 @@ impl ConvertRequest {
         encoder.set_compressed_base_image(base_image).context("cannot set base_image")?;
 
         // get gainmap
+        self.stage(report, Stage::GainMap)?;
         let apple_gainmap = Self::get_apple_gainmap_image(&lib_heif, &handle)?;
         let mut gainmap_jpg = self.create_gainmap_jpg(&apple_gainmap, apple_headroom)?;
+        report.update(|report| {
+            report.gain_map_width = Some(apple_gainmap.width());
+            report.gain_map_height = Some(apple_gainmap.height());
+            report.gain_map_bytes = Some(gainmap_jpg.len() as u64);
+        });
         let gainmap_jpg_compressed = libultrahdr_rs::CompressedImage::from_bytes(&mut gainmap_jpg);
-        let metadata = libultrahdr_rs::GainmapMetadata {
+        let gainmap_metadata = libultrahdr_rs::GainmapMetadata {
             max_content_boost: [apple_headroom; 3],
             min_content_boost: [1.0; 3],
             gamma: [1.0; 3],
@@ -270,13 +332,12 @@ impl ConvertRequest {
             hdr_capacity_max: apple_headroom,
             use_base_cg: 1,
         };
-        encoder.set_gainmap_image(gainmap_jpg_compressed, metadata)?;
+        encoder.set_gainmap_image(gainmap_jpg_compressed, gainmap_metadata)?;
 
+        self.stage(report, Stage::UltraHdrEncode)?;
         info_span!("libuhdr encoding").in_scope(|| encoder.encode().context("encode failed"))?;
         let output_img = encoder.get_encoded_stream().context("no encoded stream")?;
 
-        std::fs::write(output, output_img.as_bytes())?;
-
-        Ok(())
+        Ok((output_img.as_bytes().to_vec(), metadata))
     }
 }
@@ -0,0 +1,83 @@
+use std::path::{Path, PathBuf};
+
+/// Why a conversion failed. Variants have the path they are about, if any.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ConvertError {
+    /// The request cannot be run, e.g. the output is not a jpg
+    #[error("invalid request for {}: {reason}", path.display())]
+    InvalidRequest { path: PathBuf, reason: String },
+    /// A required option of [`super::ConvertRequestBuilder`] is not set
+    #[error("missing option: {option}")]
+    MissingOption { option: &'static str },
+    #[error("input not found: {}", path.display())]
+    InputNotFound { path: PathBuf },
+    /// The output exists, and `overwrite_existing` is not set
+    #[error("output already exists: {}", path.display())]
+    OutputExists { path: PathBuf },
+    /// The exiftool executable could not be run
+    #[error("exiftool not found: {}", path.display())]
+    ExifToolMissing { path: PathBuf },
+    /// The image is in a format the conversion does not handle, e.g. not 8-bit YUV 4:2:0
+    #[error("unsupported image {}: {reason}", path.display())]
+    UnsupportedImage { path: PathBuf, reason: String },
+    /// The image has an HDR gain map, but not the metadata to apply it
+    #[error("incomplete HDR metadata in {}: {reason}", path.display())]
+    HdrMetadata { path: PathBuf, reason: String },
+    #[error("image conversion failed for {}", path.display())]
+    Image {
+        path: PathBuf,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// `path` is None for a video in memory
+    #[error("video conversion failed for {}", path.as_deref().unwrap_or(Path::new("<memory>")).display())]
+    Video {
+        path: Option<PathBuf>,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("reading or writing metadata failed for {}", path.display())]
+    Metadata {
+        path: PathBuf,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("I/O failed for {}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("conversion cancelled")]
+    Cancelled,
+}
+
+impl ConvertError {
+    /// Keep a typed error raised inside a stage, or else wrap the error with `wrap`
+    pub(crate) fn classify(error: anyhow::Error, wrap: impl FnOnce(anyhow::Error) -> Self) -> Self {
+        let error = match error.downcast::<ConvertError>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        if error.chain().any(|e| e.is::<super::progress::Cancelled>()) {
+            return Self::Cancelled;
+        }
+        if let Some(missing) = error.chain().find_map(|e| e.downcast_ref::<crate::utils::ExifToolNotFound>()) {
+            return Self::ExifToolMissing {
+                path: missing.program.clone(),
+            };
+        }
+        wrap(error)
+    }
+
+    /// For `map_err` of the metadata backend calls
+    pub(crate) fn metadata(path: &Path) -> impl FnOnce(anyhow::Error) -> Self + '_ {
+        move |error| {
+            Self::classify(error, |source| Self::Metadata {
+                path: path.to_path_buf(),
+                source,
+            })
+        }
+    }
+}
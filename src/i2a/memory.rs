@@ -0,0 +1,97 @@
+//! Conversion of images and videos that are not files, e.g. uploads
+//!
+
+use std::{
+    io::{Read, Write},
+    ops::Deref,
+    path::Path,
+    sync::Arc,
+};
+
+use super::{ConvertError, ConvertRequestBuilder, Operation};
+
+impl ConvertRequestBuilder {
+    /// Convert an image (HEIC or JPEG) and a video in memory, returning the motion photo.
+    ///
+    /// The image is decoded and the video remuxed from memory, and the video is never written to a file.
+    /// The metadata backend works on files, so the image (to read its tags) and the key photo (to write them)
+    /// are staged in a private temporary directory, which is removed afterwards. The paths of the builder are not used.
+    ///
+    /// The inputs are shared, not copied, when given as `Arc<[u8]>`.
+    pub fn convert_bytes(self, image: impl Into<Arc<[u8]>>, video: impl Into<Arc<[u8]>>) -> Result<Vec<u8>, ConvertError> {
+        let (image, video) = (image.into(), video.into());
+        let dir = tempfile::tempdir().map_err(|source| ConvertError::Io {
+            path: std::env::temp_dir(),
+            source,
+        })?;
+        // libheif and exiftool tell the format by the extension
+        let image_path = dir.path().join(match is_heif(&image) {
+            true => "image.heic",
+            false => "image.jpg",
+        });
+        let output_path = dir.path().join("output.jpg");
+        write(&image_path, &image)?;
+
+        let mut request = self
+            .image_path(&image_path)
+            .output_path(&output_path)
+            .overwrite_existing(false)
+            .build()?;
+        request.video_path = None;
+        request.image_data = Some(Bytes(image));
+        request.video_data = Some(Bytes(video));
+        let mut video = vec![];
+        request.run(Operation::MotionPhoto, Some(&mut video))?;
+
+        // the key photo with its metadata, followed by the video
+        let mut output = std::fs::read(&output_path).map_err(|source| ConvertError::Io { path: output_path, source })?;
+        output.append(&mut video);
+        Ok(output)
+    }
+
+    /// Same as [`Self::convert_bytes`], reading the inputs to the end and writing the motion photo to `output`.
+    /// The inputs and the output are buffered in memory: a MOV is not seekable as a stream, and the output
+    /// is only complete once the metadata is written.
+    pub fn convert_stream(self, mut image: impl Read, mut video: impl Read, mut output: impl Write) -> Result<(), ConvertError> {
+        let io = |source| ConvertError::Io {
+            path: "<stream>".into(),
+            source,
+        };
+        let mut image_data = vec![];
+        image.read_to_end(&mut image_data).map_err(io)?;
+        let mut video_data = vec![];
+        video.read_to_end(&mut video_data).map_err(io)?;
+        let data = self.convert_bytes(image_data, video_data)?;
+        output.write_all(&data).map_err(io)?;
+        output.flush().map_err(io)
+    }
+}
+
+/// Input data held in memory, shared with the decoders, debug-printed by its size
+pub(crate) struct Bytes(pub Arc<[u8]>);
+
+impl Deref for Bytes {
+    type Target = Arc<[u8]>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{} bytes>", self.0.len())
+    }
+}
+
+/// Whether the data starts with an ISO BMFF `ftyp` box, as HEIC does
+fn is_heif(data: &[u8]) -> bool {
+    data.get(4..8) == Some(b"ftyp")
+}
+
+fn write(path: &Path, data: &[u8]) -> Result<(), ConvertError> {
+    std::fs::write(path, data).map_err(|source| ConvertError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
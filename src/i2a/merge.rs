@@ -1,73 +1,218 @@
 //! iOS to Android
 //!
 
-use std::path::Path;
+use std::{borrow::Cow, path::Path};
 
-use anyhow::{bail, Context, Result};
+use anyhow::Result;
 
-use super::ConvertRequest;
+use super::{report::Recorder, video, ConvertError, ConvertRequest, FileTimeMode, Operation};
+use crate::utils::{format_offset, parse_offset, PhotoMetadata, TimeShift};
 
 impl ConvertRequest {
-    /// check if the request is valid
-    pub(crate) fn check_valid(&self) -> Result<()> {
-        if !self.image_path.exists() {
-            bail!("Image does not exist");
+    /// check if the options are in range
+    pub(crate) fn check_options(&self) -> Result<(), ConvertError> {
+        let invalid = |reason: String| ConvertError::InvalidRequest {
+            path: self.output_path.clone(),
+            reason,
+        };
+        for (name, quality) in [("image_quality", self.image_quality), ("gainmap_quality", self.gainmap_quality)] {
+            if !(0..=100).contains(&quality) {
+                return Err(invalid(format!("{name} must be in [0, 100], got {quality}")));
+            }
+        }
+        if let Some(video_encode) = &self.video_encode {
+            video_encode.check().map_err(invalid)?;
         }
-        if !self.image_path.is_file() {
-            bail!("Image is not a file");
+        self.audio.check().map_err(invalid)?;
+        if let Some(video::KeyFrame::Timestamp(timestamp_us)) = self.key_frame {
+            if timestamp_us < 0 {
+                return Err(invalid(format!("key frame timestamp must not be negative, got {timestamp_us}")));
+            }
+        }
+        Ok(())
+    }
+
+    /// check if the request is valid
+    pub(crate) fn check_valid(&self, operation: Operation) -> Result<(), ConvertError> {
+        self.check_options()?;
+        let invalid = |path: &Path, reason: &str| ConvertError::InvalidRequest {
+            path: path.to_path_buf(),
+            reason: reason.to_string(),
+        };
+        match operation {
+            Operation::Image | Operation::AttachVideo if self.image_path.is_none() => {
+                return Err(ConvertError::MissingOption { option: "image_path" });
+            }
+            Operation::Image if self.key_frame.is_some() => {
+                return Err(invalid(&self.output_path, "A key frame needs a video"));
+            }
+            Operation::AttachVideo if self.key_frame.is_some() || self.is_input_heic().unwrap_or(true) => {
+                return Err(invalid(
+                    &self.output_path,
+                    "Only a jpg image can have the video attached, use convert() for a HEIC or a key frame",
+                ));
+            }
+            Operation::Video if self.image_path.is_some() => {
+                return Err(invalid(&self.output_path, "The image is not used when remuxing a video"));
+            }
+            _ => {}
         }
-        if !self.video_path.exists() {
-            bail!("Video does not exist");
+        if let Some(image_path) = &self.image_path {
+            if !image_path.exists() {
+                return Err(ConvertError::InputNotFound { path: image_path.clone() });
+            }
+            if !image_path.is_file() {
+                return Err(invalid(image_path, "Image is not a file"));
+            }
         }
-        if !self.video_path.is_file() {
-            bail!("Video is not a file");
+        if operation != Operation::Image && self.video_data.is_none() {
+            let video_path = self.video()?;
+            if !video_path.exists() {
+                return Err(ConvertError::InputNotFound {
+                    path: video_path.to_path_buf(),
+                });
+            }
+            if !video_path.is_file() {
+                return Err(invalid(video_path, "Video is not a file"));
+            }
         }
         if self.output_path.is_dir() {
-            bail!("Output path is a directory");
+            return Err(invalid(&self.output_path, "Output path is a directory"));
+        }
+        let output_ext = self.output_path.extension().unwrap_or_default();
+        match operation {
+            Operation::Video if !output_ext.eq_ignore_ascii_case("mp4") => {
+                return Err(invalid(&self.output_path, "Output path must have mp4 extension"));
+            }
+            Operation::Video => {}
+            _ if !["jpg", "jpeg"].iter().any(|e| output_ext.eq_ignore_ascii_case(e)) => {
+                return Err(invalid(&self.output_path, "Output path must have jpg extension"));
+            }
+            _ => {}
         }
-        let output_ext = self.output_path.extension().context("output path has no extension")?;
-        anyhow::ensure!(
-            ["jpg", "jpeg"].iter().any(|e| output_ext.eq_ignore_ascii_case(e)),
-            "Output path must have jpg extension"
-        );
         if self.io_same_file() {
-            bail!("Input image and output image is same file");
+            return Err(invalid(&self.output_path, "Input image and output image is same file"));
         }
         if self.output_path.exists() && !self.overwrite_existing {
-            bail!("Output file already exists");
+            return Err(ConvertError::OutputExists {
+                path: self.output_path.clone(),
+            });
         }
-        let parent = self.output_path.parent().context("Invalid output path: no parent")?;
-        if !parent.exists() {
-            bail!("Output path parent does not exist. You must create it with proper permissions.");
+        if !self.output_path.parent().is_some_and(Path::exists) {
+            return Err(invalid(
+                &self.output_path,
+                "Output path parent does not exist. You must create it with proper permissions.",
+            ));
         }
         Ok(())
     }
 
-    /// Copy image_path to output_path
-    pub(crate) fn copy_image(&self) -> anyhow::Result<()> {
-        let mut output = std::fs::File::create(&self.output_path)?;
-        let mut image = std::fs::File::open(&self.image_path)?;
-        std::io::copy(&mut image, &mut output)?;
-        std::mem::drop(output);
-        Ok(())
+    /// Read the jpg image_path as is, to be the output
+    pub(crate) fn read_image(&self, report: &mut Recorder, image_path: &Path) -> anyhow::Result<Vec<u8>> {
+        let image = match self.image_data.as_ref() {
+            Some(data) => data.to_vec(),
+            None => std::fs::read(image_path)?,
+        };
+        report.update(|report| report.base_image_bytes = image.len() as u64);
+        Ok(image)
     }
-    pub(crate) fn append_video(&self, video: &Path) -> anyhow::Result<()> {
+    /// Append the video to the output file, or to `video_sink` if set. Returns the size of the video.
+    pub(crate) fn append_video(&self, video: &mut impl std::io::Read, video_sink: Option<&mut Vec<u8>>) -> anyhow::Result<u64> {
+        if let Some(video_sink) = video_sink {
+            return Ok(std::io::copy(video, video_sink)?);
+        }
         let mut output = std::fs::File::options().append(true).truncate(false).open(&self.output_path)?;
-        let mut video = std::fs::File::open(video)?;
-        std::io::copy(&mut video, &mut output)?;
-        Ok(())
+        Ok(std::io::copy(video, &mut output)?)
+    }
+
+    /// Set the output file times, from the capture date or from the source file as per `file_times`.
+    /// `image` is the metadata of the source image, if it was already read.
+    pub(crate) fn set_output_times(&self, image: Option<&PhotoMetadata>) -> Result<()> {
+        if self.file_times == FileTimeMode::CaptureDate {
+            match self.capture_time(image)? {
+                Some(time) => return Self::set_file_times(&self.output_path, time, Some(time)),
+                None => warn!("No capture date found, using the file times of the source"),
+            }
+        }
+        let src_meta = self.source_path().metadata()?;
+        Self::set_file_times(&self.output_path, src_meta.modified()?, src_meta.created().ok())
+    }
+
+    /// The metadata of the source image: `image` if it was already read, else read now. None without an image.
+    fn image_metadata<'a>(&self, image: Option<&'a PhotoMetadata>) -> Result<Option<Cow<'a, PhotoMetadata>>> {
+        match (image, self.image_path.as_deref()) {
+            (Some(image), _) => Ok(Some(Cow::Borrowed(image))),
+            (None, Some(image_path)) => {
+                let read = self.metadata.read(image_path).map_err(ConvertError::metadata(image_path))?;
+                Ok(Some(Cow::Owned(read)))
+            }
+            (None, None) => Ok(None),
+        }
     }
 
-    pub(crate) fn sync_file_times(&self, src: &Path, dst: &Path) -> Result<()> {
+    /// Capture time of the image (with its time zone offset), or else of the video, with the time shift applied
+    fn capture_time(&self, image: Option<&PhotoMetadata>) -> Result<Option<std::time::SystemTime>> {
+        let image = self.image_metadata(image)?;
+        let time = match (image.as_deref().and_then(PhotoMetadata::capture_time), self.video_input()) {
+            (Some(time), _) => Some(time),
+            (None, Ok(input)) => video::VideoUtils::get_creation_time(input)?,
+            (None, Err(_)) => None,
+        };
+        Ok(time.map(|time| self.time_shift.map_or(time, |shift| shift.apply(time))))
+    }
+
+    /// Seconds to move the local dates of the output image by, found before the output is written.
+    /// To change the time zone, the dates are taken to be in the `OffsetTimeOriginal` of the image,
+    /// or else in the offset of the video creation date, which the video dates are shifted from.
+    pub(crate) fn image_shift_seconds(&self, shift: TimeShift, image: Option<&PhotoMetadata>) -> Result<i64> {
+        if let Some(seconds) = shift.local_seconds(None) {
+            return Ok(seconds);
+        }
+        let image = self.image_metadata(image)?;
+        let offset = image
+            .as_ref()
+            .and_then(|image| image.offset_time_original.as_deref())
+            .and_then(parse_offset);
+        let offset = match (offset, self.video_input()) {
+            (Some(offset), _) => Some(offset),
+            (None, Ok(input)) => video::VideoUtils::get_time_zone_offset(input)?,
+            (None, Err(_)) => None,
+        };
+        match shift.local_seconds(offset) {
+            Some(seconds) => Ok(seconds),
+            None => Err(ConvertError::InvalidRequest {
+                path: self.source_path().to_path_buf(),
+                reason: "Neither the image nor the video has a time zone offset to change the time zone from".to_string(),
+            }
+            .into()),
+        }
+    }
+
+    /// Shift the capture dates of the output image by `seconds`, see [`Self::image_shift_seconds`]
+    pub(crate) fn shift_image_dates(&self, shift: TimeShift, seconds: i64) -> Result<()> {
+        let new_offset = match shift {
+            TimeShift::Offset(_) => None,
+            TimeShift::TimeZone(zone) => Some(format_offset(zone, true)),
+        };
+        debug!(seconds, ?new_offset, "shift image dates");
+        self.metadata.shift_dates(&self.output_path, seconds, new_offset.as_deref())
+    }
+
+    /// `created` is only set on macOS and Windows
+    fn set_file_times(dst: &Path, modified: std::time::SystemTime, created: Option<std::time::SystemTime>) -> Result<()> {
         #[cfg(target_os = "macos")]
         use std::os::macos::fs::FileTimesExt;
         #[cfg(target_os = "windows")]
         use std::os::windows::fs::FileTimesExt;
 
-        let src_meta = src.metadata()?;
-        let file_times = std::fs::FileTimes::new().set_modified(src_meta.modified()?);
+        let file_times = std::fs::FileTimes::new().set_modified(modified);
         #[cfg(any(target_os = "macos", target_os = "windows"))]
-        let file_times = file_times.set_created(src_meta.created()?);
+        let file_times = match created {
+            Some(created) => file_times.set_created(created),
+            None => file_times,
+        };
+        #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+        let _ = created;
 
         let dst = std::fs::OpenOptions::new()
             .read(true)
@@ -80,37 +225,15 @@ impl ConvertRequest {
     }
 
     pub(crate) fn output_is_motion_photo(&self) -> Result<bool> {
-        let micro_video = self.exif_tool().get_value(&self.output_path, "XMP-GCamera:MicroVideo")?;
-        Ok(micro_video.is_some())
+        let is_motion_photo = self.metadata.is_motion_photo(&self.output_path);
+        Ok(is_motion_photo.map_err(ConvertError::metadata(&self.output_path))?)
     }
 
-    pub(crate) fn update_motion_photo_exif(&self, video_path: &Path) -> anyhow::Result<()> {
-        // release exiftool.config
-        use std::io::Write;
-        const EXIFTOOL_CONFIG: &str = include_str!("exiftool.config");
-        let mut temp_config = tempfile::NamedTempFile::new()?;
-        temp_config.write_all(EXIFTOOL_CONFIG.as_bytes())?;
-        temp_config.flush()?;
-
-        let mut cmd = self.exif_tool().command();
-        let video_size = video_path.metadata()?.len();
-        cmd.args(["-config", temp_config.path().to_str().unwrap()])
-            .args([
-                "-XMP-GCamera:MicroVideo=1",
-                "-XMP-GCamera:MicroVideoVersion=1",
-                "-XMP-GCamera:MicroVideoPresentationTimestampUs=1500000",
-            ])
-            .arg(format!("-XMP-GCamera:MicroVideoOffset={video_size}"))
-            .arg("-XiaomiTag=1")
-            .arg("-overwrite_original")
-            .arg("-m") // ignore minor error, as per https://exiftool.org/forum/index.php?topic=7341.0
-            .arg(&self.output_path);
-        let output = cmd.output().context("Run exiftool failed when writing tags")?;
-        if !output.status.success() {
-            info!("Failed to run task: {:?}", cmd);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            bail!("Run exiftool failed when writing exif tags: {}", stderr);
-        }
+    /// `video_size` is the size of the appended video, and `presentation_timestamp_us` is the time of the key photo in it
+    pub(crate) fn update_motion_photo_exif(&self, video_size: u64, presentation_timestamp_us: i64) -> anyhow::Result<()> {
+        self.metadata
+            .write_motion_photo(&self.output_path, video_size, presentation_timestamp_us)
+            .map_err(ConvertError::metadata(&self.output_path))?;
 
         /* 小米的 tag 写不进去，放弃 exiv2 库
         let metadata =
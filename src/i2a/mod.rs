@@ -1,31 +1,213 @@
 use anyhow::Context;
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+mod builder;
 mod convert;
+mod error;
+mod memory;
 mod merge;
+mod mp4;
+pub mod progress;
+mod report;
 mod utils;
 pub mod video;
 
+pub use builder::ConvertRequestBuilder;
+pub use error::ConvertError;
+use report::Recorder;
+pub use report::{ConvertReport, StageDuration};
+
+/// A conversion of an image and a video to a motion photo, or of either alone. Made with [`ConvertRequest::builder`],
+/// which checks the options: they can be read, but not changed, afterwards.
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct ConvertRequest {
-    pub image_path: PathBuf,
-    pub video_path: PathBuf,
-    pub output_path: PathBuf,
-    // 用 exiftool 实现 exif 写入
-    pub exiftool_path: Option<PathBuf>,
-
-    pub overwrite_existing: bool,
+    image_path: Option<PathBuf>,
+    video_path: Option<PathBuf>,
+    output_path: PathBuf,
+    metadata: Arc<dyn crate::utils::MetadataBackend>,
+    overwrite_existing: bool,
+    image_quality: i32,
+    gainmap_quality: i32,
+    video_trim: Option<video::VideoTrim>,
+    exact_trim: bool,
+    video_encode: Option<video::VideoEncodeOptions>,
+    video_rotation: video::VideoRotation,
+    audio: video::AudioOptions,
+    strip_audio: bool,
+    always_remux: bool,
+    video_hdr: video::HdrPolicy,
+    key_frame: Option<video::KeyFrame>,
+    metadata_policy: crate::utils::MetadataPolicy,
+    time_shift: Option<crate::utils::TimeShift>,
+    file_times: FileTimeMode,
+    progress: progress::Progress,
+    /// The image, when converting from memory. `image_path` is still read by the metadata backend.
+    image_data: Option<memory::Bytes>,
+    /// The video, when converting from memory. It is never written to a file.
+    video_data: Option<memory::Bytes>,
+}
 
+/// The options of a request, which are set with [`ConvertRequestBuilder`]
+impl ConvertRequest {
+    /// None takes the key photo from the video
+    pub fn image_path(&self) -> Option<&Path> {
+        self.image_path.as_deref()
+    }
+    /// None for [`Self::convert_image`]
+    pub fn video_path(&self) -> Option<&Path> {
+        self.video_path.as_deref()
+    }
+    /// A jpg, or an mp4 for [`Self::remux_video`]
+    pub fn output_path(&self) -> &Path {
+        &self.output_path
+    }
+    /// Reads and writes the photo metadata, e.g. `Arc::new(ExifTool::new())`
+    pub fn metadata(&self) -> &Arc<dyn crate::utils::MetadataBackend> {
+        &self.metadata
+    }
+    pub fn overwrite_existing(&self) -> bool {
+        self.overwrite_existing
+    }
     /// [0, 100]
-    pub image_quality: i32,
+    pub fn image_quality(&self) -> i32 {
+        self.image_quality
+    }
     /// [0, 100]
-    pub gainmap_quality: i32,
+    pub fn gainmap_quality(&self) -> i32 {
+        self.gainmap_quality
+    }
+    /// Trim the embedded video around the key photo. None keeps the whole video.
+    pub fn video_trim(&self) -> Option<video::VideoTrim> {
+        self.video_trim
+    }
+    /// Re-encode the video if needed so that the trim starts exactly, instead of at the keyframe before it
+    pub fn exact_trim(&self) -> bool {
+        self.exact_trim
+    }
+    /// Re-encode the embedded video, e.g. to H.264 for older Android phones. None copies it.
+    pub fn video_encode(&self) -> Option<&video::VideoEncodeOptions> {
+        self.video_encode.as_ref()
+    }
+    /// What to do with a rotated video
+    pub fn video_rotation(&self) -> video::VideoRotation {
+        self.video_rotation
+    }
+    /// How to transcode the audio, and which audio codecs are kept untouched
+    pub fn audio(&self) -> &video::AudioOptions {
+        &self.audio
+    }
+    /// Drop the audio track, e.g. for privacy. The video is still copied if possible.
+    pub fn strip_audio(&self) -> bool {
+        self.strip_audio
+    }
+    /// Always remux the video into a clean MP4, instead of appending a compatible MOV verbatim
+    pub fn always_remux(&self) -> bool {
+        self.always_remux
+    }
+    /// What to do with HDR (Dolby Vision / HLG) video
+    pub fn video_hdr(&self) -> video::HdrPolicy {
+        self.video_hdr
+    }
+    /// Use this frame of the video as the key photo, instead of the image. The image still provides the metadata.
+    /// None uses the image, or the frame at the live photo key photo time if there is no image.
+    pub fn key_frame(&self) -> Option<video::KeyFrame> {
+        self.key_frame
+    }
+    /// Which metadata of the image and the video is kept
+    pub fn metadata_policy(&self) -> crate::utils::MetadataPolicy {
+        self.metadata_policy
+    }
+    /// Correct the capture dates of the image and the video
+    pub fn time_shift(&self) -> Option<crate::utils::TimeShift> {
+        self.time_shift
+    }
+    /// Where the output file times come from
+    pub fn file_times(&self) -> FileTimeMode {
+        self.file_times
+    }
+    /// Observe the stages of the conversion, or cancel it
+    pub fn progress(&self) -> &progress::Progress {
+        &self.progress
+    }
+}
+
+/// Where the output file times come from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FileTimeMode {
+    /// Copy the modification (and creation) times of the source file
+    #[default]
+    Source,
+    /// The capture date with its time zone: `DateTimeOriginal` + `OffsetTimeOriginal` of the image,
+    /// or the QuickTime creation date of the video. Falls back to `Source` if there is none.
+    CaptureDate,
+}
+
+/// The key photo of the output, before its metadata is written
+pub(crate) struct KeyPhoto {
+    /// Time of the key photo in the video, None for an image of a live photo
+    pub timestamp_us: Option<i64>,
+    /// Size of the video frame, if the key photo is one
+    pub frame_size: Option<(usize, usize)>,
+    /// Metadata of the source image, if it was read to convert it
+    pub metadata: Option<crate::utils::PhotoMetadata>,
+    /// Seconds to move the local dates of the output by, for the time shift of the request
+    pub shift_seconds: Option<i64>,
+}
+
+/// What a request makes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    /// Image (or video frame) + video => motion photo
+    MotionPhoto,
+    /// HEIC => Ultra HDR jpg, or a copy of a jpg
+    Image,
+    /// jpg + video => motion photo, without converting the image
+    AttachVideo,
+    /// Live photo MOV => MP4
+    Video,
 }
 
 impl ConvertRequest {
     /// Input and output is same file
     pub fn io_same_file(&self) -> bool {
-        self.image_path.as_os_str().eq_ignore_ascii_case(self.output_path.as_os_str())
+        self.image_path
+            .as_ref()
+            .is_some_and(|image_path| image_path.as_os_str().eq_ignore_ascii_case(self.output_path.as_os_str()))
+    }
+
+    /// The image if there is one, else the video. Output file times are taken from it.
+    pub fn source_path(&self) -> &Path {
+        self.image_path
+            .as_deref()
+            .or(self.video_path.as_deref())
+            .unwrap_or(&self.output_path)
+    }
+
+    /// The video, which all operations but [`Self::convert_image`] need
+    fn video(&self) -> Result<&Path, ConvertError> {
+        self.video_path
+            .as_deref()
+            .ok_or(ConvertError::MissingOption { option: "video_path" })
+    }
+
+    /// The video to decode, from memory if it was given so
+    fn video_input(&self) -> Result<video::VideoInput<'_>, ConvertError> {
+        match self.video_data.as_ref() {
+            Some(data) => Ok(video::VideoInput::Memory(data)),
+            None => Ok(self.video()?.into()),
+        }
+    }
+
+    /// Report entering a stage, failing if cancelled
+    fn stage(&self, report: &mut Recorder, stage: progress::Stage) -> anyhow::Result<()> {
+        self.progress.stage(stage)?;
+        report.stage(stage);
+        Ok(())
     }
 
     fn is_input_heic(&self) -> anyhow::Result<bool> {
@@ -33,113 +215,302 @@ impl ConvertRequest {
         Ok(ans)
     }
 
-    pub fn convert(&self) -> anyhow::Result<()> {
+    /// Make a motion photo of the image (or a video frame) and the video
+    pub fn convert(&self) -> Result<ConvertReport, ConvertError> {
+        self.run(Operation::MotionPhoto, None)
+    }
+
+    /// Convert the image alone: a HEIC to an Ultra HDR jpg, or a copy of a jpg. The video is not used.
+    pub fn convert_image(&self) -> Result<ConvertReport, ConvertError> {
+        self.run(Operation::Image, None)
+    }
+
+    /// Append the video to a jpg image as is, making a motion photo
+    pub fn attach_video(&self) -> Result<ConvertReport, ConvertError> {
+        self.run(Operation::AttachVideo, None)
+    }
+
+    /// Remux (or transcode) the video alone into an mp4, with the video, audio and metadata options of the request.
+    /// The video is trimmed around the key frame time if given, else around the live photo key photo.
+    pub fn remux_video(&self) -> Result<ConvertReport, ConvertError> {
+        self.run(Operation::Video, None)
+    }
+
+    /// `video_sink` keeps the video of a motion photo in memory, instead of appending it to the output file
+    fn run(&self, operation: Operation, video_sink: Option<&mut Vec<u8>>) -> Result<ConvertReport, ConvertError> {
         debug!(
+            ?operation,
             "Running convert request {} + {} => {}",
-            self.image_path.display(),
-            self.video_path.display(),
+            self.image_path.as_deref().unwrap_or(Path::new("-")).display(),
+            self.video_path.as_deref().unwrap_or(Path::new("-")).display(),
             self.output_path.display(),
         );
 
-        self.check_valid().context("request arguments invalid")?;
+        self.check_valid(operation)?;
+        let mut report = Recorder::default();
         let t = std::time::Instant::now();
+        let image_error = |e| {
+            ConvertError::classify(e, |source| ConvertError::Image {
+                path: self.source_path().to_path_buf(),
+                source,
+            })
+        };
+        let video_error = |e| {
+            ConvertError::classify(e, |source| ConvertError::Video {
+                path: self.video_path.clone(),
+                source,
+            })
+        };
 
-        // 1. convert image
-        let mut guard = self.make_hdr()?;
+        // 1. convert image, or video alone
+        let (data, key_photo) = match operation {
+            Operation::Video => (self.make_video(&mut report).map_err(video_error)?, None),
+            _ => {
+                let (jpg, key_photo) = self.make_hdr(&mut report).map_err(image_error)?;
+                (jpg, Some(key_photo))
+            }
+        };
+        // an existing output is only replaced once the conversion can't fail early
+        std::fs::write(&self.output_path, data).map_err(|source| ConvertError::Io {
+            path: self.output_path.clone(),
+            source,
+        })?;
+        let mut guard = utils::Guard::new(|| {
+            // in case rest failed, remove generated output
+            std::fs::remove_file(&self.output_path).ok();
+        });
 
-        // 2. append video
-        self.make_motion()?;
+        // 2. metadata, and append video
+        match (operation, key_photo) {
+            (Operation::MotionPhoto | Operation::AttachVideo, Some(key_photo)) => {
+                self.write_key_photo_metadata(&mut report, &key_photo).map_err(image_error)?;
+                let key_photo_us = key_photo.timestamp_us.unwrap_or_else(|| self.live_photo_key_photo_us());
+                self.make_motion(&mut report, key_photo_us, video_sink).map_err(video_error)?;
+                self.set_output_times(key_photo.metadata.as_ref()).map_err(video_error)?;
+            }
+            (_, Some(key_photo)) => {
+                self.write_key_photo_metadata(&mut report, &key_photo).map_err(image_error)?;
+                self.set_output_times(key_photo.metadata.as_ref()).map_err(image_error)?;
+            }
+            (_, None) => self.set_output_times(None).map_err(video_error)?,
+        }
 
         #[rustfmt::skip]
-        let output_size = self.output_path.metadata().context("Output is gone")?.len() as f32 / 1024.0 / 1024.0;
+        let output_size = self.output_path.metadata().map_err(|source| ConvertError::Io { path: self.output_path.clone(), source })?.len() as f32 / 1024.0 / 1024.0;
         info!(
-            "convert success in {:.2?}: {} + {} => {} (size={output_size:.2} MiB)",
+            "convert success in {:.2?}: {} => {} (size={output_size:.2} MiB)",
             t.elapsed(),
-            self.image_path.display(),
-            self.video_path.display(),
+            self.source_path().display(),
             self.output_path.display(),
         );
 
         guard.cancel();
-        Ok(())
+        Ok(report.finish())
     }
 
     pub fn delete_original(&self) -> anyhow::Result<()> {
-        if !self.io_same_file() {
-            std::fs::remove_file(&self.image_path).context("delete original image failed")?;
+        if let Some(image_path) = self.image_path.as_ref().filter(|_| !self.io_same_file()) {
+            std::fs::remove_file(image_path).context("delete original image failed")?;
+        }
+        if let Some(video_path) = self.video_path.as_ref() {
+            std::fs::remove_file(video_path).context("delete original video failed")?;
         }
-        std::fs::remove_file(&self.video_path).context("delete original video failed")?;
         Ok(())
     }
 
-    fn exif_tool(&self) -> crate::utils::ExifTool {
-        match self.exiftool_path.as_ref() {
-            Some(path) => crate::utils::ExifTool::with_path(path.clone()),
-            None => crate::utils::ExifTool::new(),
+    /// Returns the jpg to be the output, and the key photo it is made of
+    fn make_hdr(&self, report: &mut Recorder) -> anyhow::Result<(Vec<u8>, KeyPhoto)> {
+        let t = std::time::Instant::now();
+        let (jpg, mut key_photo) = match (&self.image_path, self.key_frame) {
+            (Some(image_path), None) => {
+                let (jpg, metadata) = match self.is_input_heic()? {
+                    true => {
+                        let (jpg, metadata) = self.convert_heic_to_jpg(report, image_path)?;
+                        (jpg, Some(metadata))
+                    }
+                    false => (self.read_image(report, image_path)?, None),
+                };
+                let key_photo = KeyPhoto {
+                    timestamp_us: None,
+                    frame_size: None,
+                    metadata,
+                    shift_seconds: None,
+                };
+                (jpg, key_photo)
+            }
+            (_, key_frame) => {
+                let key_frame = key_frame.unwrap_or_else(|| video::KeyFrame::Timestamp(self.live_photo_key_photo_us()));
+                self.convert_video_frame_to_jpg(report, key_frame)?
+            }
+        };
+        debug!("jpg encoded (with HDR effect), time={:?}", t.elapsed());
+        // a time shift that cannot be applied fails before anything is written
+        if let Some(shift) = self.time_shift {
+            key_photo.shift_seconds = Some(self.image_shift_seconds(shift, key_photo.metadata.as_ref())?);
         }
+        Ok((jpg, key_photo))
     }
 
-    fn make_hdr(&self) -> anyhow::Result<utils::Guard<impl FnOnce() + '_>> {
-        let t = std::time::Instant::now();
-        match self.is_input_heic()? {
-            true => self.convert_heic_to_jpg()?,
-            false => self.copy_image()?,
+    /// Write the metadata of the output jpg: the tags of the source, the policy and the time shift
+    fn write_key_photo_metadata(&self, report: &mut Recorder, key_photo: &KeyPhoto) -> anyhow::Result<()> {
+        let output = self.output_path.as_path();
+        self.stage(report, progress::Stage::Metadata)?;
+        match (self.image_path.as_deref(), key_photo.frame_size) {
+            // a jpg is copied with its tags
+            (Some(_), None) if !self.is_input_heic()? => {}
+            (Some(image_path), None) => self
+                .metadata
+                .copy_tags(image_path, output)
+                .map_err(ConvertError::metadata(output))?,
+            // the tags of the image describe its own size, not the size of the frame
+            (Some(image_path), Some((width, height))) => self
+                .metadata
+                .copy_tags(image_path, output)
+                .and_then(|()| self.metadata.set_image_size(output, width, height))
+                .map_err(ConvertError::metadata(output))?,
+            (None, _) => self
+                .metadata
+                .copy_video_tags(self.video()?, output)
+                .map_err(ConvertError::metadata(output))?,
         }
-        let guard = utils::Guard::new(|| {
-            // in case rest failed, remove generated output
-            std::fs::remove_file(&self.output_path).ok();
-        });
-        debug!("jpg ensured (with HDR effect), time={:?}", t.elapsed());
-        Ok(guard)
+        if self.metadata_policy != crate::utils::MetadataPolicy::KeepAll {
+            self.metadata
+                .apply_policy(output, self.metadata_policy)
+                .map_err(ConvertError::metadata(output))?;
+        }
+        if let (Some(shift), Some(seconds)) = (self.time_shift, key_photo.shift_seconds) {
+            self.shift_image_dates(shift, seconds).map_err(ConvertError::metadata(output))?;
+        }
+        Ok(())
     }
 
     #[instrument(skip_all)]
-    fn make_motion(&self) -> anyhow::Result<()> {
+    fn make_motion(&self, report: &mut Recorder, key_photo_us: i64, video_sink: Option<&mut Vec<u8>>) -> anyhow::Result<()> {
         if self.output_is_motion_photo()? {
             warn!("Output is already a motion photo, skip append video");
-            self.sync_file_times(&self.image_path, &self.output_path)?;
             return Ok(());
         }
 
+        self.stage(report, progress::Stage::Video)?;
         // convert mov to mp4 (and ensure audio codec is supported)
-        let Some(audio_codec) = video::VideoUtils::get_audio_codec(&self.video_path)? else {
-            debug!("no audio in video, append");
-            self.append_video(&self.video_path)?;
-            self.update_motion_photo_exif(&self.video_path)?;
-            self.sync_file_times(&self.image_path, &self.output_path)?;
-            return Ok(());
-        };
-        debug!(%audio_codec, "input video");
-        if audio_codec == "aac" || audio_codec == "ac3" {
-            self.append_video(&self.video_path)?;
-            self.update_motion_photo_exif(&self.video_path)?;
-            self.sync_file_times(&self.image_path, &self.output_path)?;
+        let input = self.video_input()?;
+        // convert in memory, so that nothing is written next to the (maybe read-only) source
+        let request = self.video_remux_request(video::VideoOutput::Memory, key_photo_us)?;
+        if !self.always_remux && !request.needs_remux()? {
+            let video_size = match input {
+                video::VideoInput::File(video_path) => self.append_video(&mut std::fs::File::open(video_path)?, video_sink)?,
+                video::VideoInput::Memory(data) => self.append_video(&mut &data[..], video_sink)?,
+            };
+            let audio_codec = video::VideoUtils::get_audio_codec(input)?;
+            report.update(|report| {
+                report.audio_codec = audio_codec;
+                report.video_offset = video_size;
+                report.presentation_timestamp_us = key_photo_us;
+            });
+            self.stage(report, progress::Stage::Metadata)?;
+            self.update_motion_photo_exif(video_size, key_photo_us)?;
             return Ok(());
         }
 
-        let video_name = self.video_path.file_stem().context("parse video path filename failed")?;
-        let tmp_video_name = format!("{}-aac-converting.mp4", video_name.to_string_lossy());
-        let tmp_video = self.video_path.with_file_name(tmp_video_name);
-        anyhow::ensure!(!tmp_video.exists(), "tempfile exists");
-        let _guard = utils::Guard::new(|| {
-            std::fs::remove_file(&tmp_video).ok();
+        let converted = info_span!("remux_video")
+            .in_scope(|| request.execute())
+            .context("remux video failed")?;
+        let video::ConvertedVideo {
+            range: kept,
+            data,
+            video_transcoded,
+            audio_codec,
+            audio_transcoded,
+        } = converted;
+        let data = data.context("converted video is not in memory")?;
+        debug!(?kept, video_transcoded, size = data.len(), "video converted");
+
+        self.append_video(&mut data.as_slice(), video_sink)?;
+        self.stage(report, progress::Stage::Metadata)?;
+        let presentation_timestamp_us = (key_photo_us - kept.start_us).max(0);
+        report.update(|report| {
+            report.audio_codec = audio_codec;
+            report.audio_transcoded = audio_transcoded;
+            report.video_offset = data.len() as u64;
+            report.presentation_timestamp_us = presentation_timestamp_us;
         });
+        self.update_motion_photo_exif(data.len() as u64, presentation_timestamp_us)?;
+        Ok(())
+    }
 
-        info_span!("transcode_aac_audio")
-            .in_scope(|| {
-                video::VideoAudioEncodeRequest {
-                    input: &self.video_path,
-                    output: &tmp_video,
-                    bit_rate: 128 << 10,
-                    encoder: "aac",
-                }
-                .execute()
-            })
-            .context("convert video audio to aac failed")?;
+    /// Remux the video alone, to be the output
+    #[instrument(skip_all)]
+    fn make_video(&self, report: &mut Recorder) -> anyhow::Result<Vec<u8>> {
+        self.stage(report, progress::Stage::Video)?;
+        let key_photo_us = match self.key_frame {
+            Some(video::KeyFrame::Timestamp(timestamp_us)) => timestamp_us,
+            _ => self.live_photo_key_photo_us(),
+        };
+        let video::ConvertedVideo {
+            range: kept,
+            data,
+            video_transcoded,
+            audio_codec,
+            audio_transcoded,
+        } = self
+            .video_remux_request(video::VideoOutput::Memory, key_photo_us)?
+            .execute()
+            .context("remux video failed")?;
+        debug!(?kept, video_transcoded, "video converted");
+        report.update(|report| {
+            report.audio_codec = audio_codec;
+            report.audio_transcoded = audio_transcoded;
+        });
+        data.context("converted video is not in memory")
+    }
 
-        self.append_video(&tmp_video)?;
-        self.update_motion_photo_exif(&tmp_video)?;
-        self.sync_file_times(&self.image_path, &self.output_path)?;
-        Ok(())
+    /// Time of the key photo in the live photo video, from its still image time if it has one
+    fn live_photo_key_photo_us(&self) -> i64 {
+        let Ok(input) = self.video_input() else {
+            return video::LIVE_PHOTO_KEY_PHOTO_US;
+        };
+        match video::VideoUtils::get_still_image_time_us(input) {
+            Ok(Some(still_image_time_us)) => still_image_time_us,
+            Ok(None) => video::LIVE_PHOTO_KEY_PHOTO_US,
+            Err(e) => {
+                warn!("read still image time failed, using the default key photo time: {e:?}");
+                video::LIVE_PHOTO_KEY_PHOTO_US
+            }
+        }
+    }
+
+    /// Remux of the video with the options of the request, trimmed around `key_photo_us`
+    fn video_remux_request<'a>(
+        &'a self,
+        output: video::VideoOutput<'a>,
+        key_photo_us: i64,
+    ) -> anyhow::Result<video::VideoRemuxRequest<'a>> {
+        let input = self.video_input()?;
+        let trim = match self.video_trim {
+            Some(video_trim) => {
+                let duration_us = video::VideoUtils::get_duration_us(input)?;
+                Some(video_trim.range(duration_us, key_photo_us))
+            }
+            None => None,
+        };
+        Ok(video::VideoRemuxRequest {
+            input,
+            output,
+            trim,
+            exact_trim: self.exact_trim,
+            video: match &self.video_encode {
+                Some(options) => video::StreamAction::Transcode(options.clone()),
+                None => video::StreamAction::Copy,
+            },
+            audio: match self.strip_audio {
+                true => video::StreamAction::Drop,
+                false => video::StreamAction::Transcode(self.audio.clone()),
+            },
+            rotation: self.video_rotation,
+            hdr: self.video_hdr,
+            progress: self.progress.clone(),
+            metadata: self.metadata_policy,
+            time_shift: self.time_shift,
+        })
     }
 }
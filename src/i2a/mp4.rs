@@ -0,0 +1,178 @@
+//! Minimal ISO BMFF (MP4 / MOV) box handling, for what ffmpeg can't do on a buffer in memory
+use anyhow::{bail, Context, Result};
+use std::{
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+};
+
+/// Boxes whose payload is a list of boxes, down to the chunk offset tables
+const CONTAINERS: [&[u8; 4]; 5] = [b"moov", b"trak", b"mdia", b"minf", b"stbl"];
+
+/// A box in a buffer
+#[derive(Debug, Clone)]
+pub(crate) struct Mp4Box {
+    pub kind: [u8; 4],
+    /// The whole box, header included
+    pub range: Range<usize>,
+    /// The payload, after the header
+    pub body: Range<usize>,
+}
+
+/// The boxes in `data[range]`, in order
+pub(crate) fn boxes(data: &[u8], range: Range<usize>) -> Result<Vec<Mp4Box>> {
+    let mut boxes = vec![];
+    let mut offset = range.start;
+    while offset < range.end {
+        let header = data.get(offset..offset + 8).context("truncated box header")?;
+        let kind: [u8; 4] = header[4..8].try_into().unwrap();
+        let (size, header_size) = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+            // extends to the end
+            0 => (range.end - offset, 8),
+            1 => {
+                let size = data.get(offset + 8..offset + 16).context("truncated box header")?;
+                let size = u64::from_be_bytes(size.try_into().unwrap());
+                (usize::try_from(size).context("box size overflows")?, 16)
+            }
+            size => (size as usize, 8),
+        };
+        let end = offset.checked_add(size).filter(|&end| size >= header_size && end <= range.end);
+        let Some(end) = end else {
+            bail!("invalid size {size} of box {}", String::from_utf8_lossy(&kind));
+        };
+        boxes.push(Mp4Box {
+            kind,
+            range: offset..end,
+            body: offset + header_size..end,
+        });
+        offset = end;
+    }
+    Ok(boxes)
+}
+
+/// Move the `moov` box in front of the media data, right after `ftyp`, like the `faststart` flag of the muxer.
+/// The chunk offsets in `moov` are moved along with the media data.
+pub(crate) fn faststart(data: Vec<u8>) -> Result<Vec<u8>> {
+    let top = boxes(&data, 0..data.len())?;
+    let moov_idx = top.iter().position(|b| &b.kind == b"moov").context("no moov box")?;
+    let insert_at = match top.first() {
+        Some(first) if &first.kind == b"ftyp" => first.range.end,
+        _ => 0,
+    };
+    let moov = top[moov_idx].range.clone();
+    if !top[..moov_idx].iter().any(|b| &b.kind == b"mdat") {
+        // already in front of the media data
+        return Ok(data);
+    }
+
+    // only the data between the insert point and moov moves
+    let mut moved = data[moov.clone()].to_vec();
+    let shift = moved.len() as u64;
+    let relative = boxes(&moved, 0..moved.len())?;
+    shift_chunk_offsets(&mut moved, &relative[0], insert_at as u64..moov.start as u64, shift)?;
+
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&data[..insert_at]);
+    output.extend_from_slice(&moved);
+    output.extend_from_slice(&data[insert_at..moov.start]);
+    output.extend_from_slice(&data[moov.end..]);
+    Ok(output)
+}
+
+/// Add `shift` to the `stco` / `co64` entries inside `container` that point into `moved`
+fn shift_chunk_offsets(data: &mut [u8], container: &Mp4Box, moved: Range<u64>, shift: u64) -> Result<()> {
+    for child in boxes(data, container.body.clone())? {
+        match &child.kind {
+            kind if CONTAINERS.contains(&kind) => shift_chunk_offsets(data, &child, moved.clone(), shift)?,
+            b"stco" | b"co64" => {
+                let entry_size = if &child.kind == b"stco" { 4 } else { 8 };
+                // version and flags, then the entry count
+                let count = data
+                    .get(child.body.start + 4..child.body.start + 8)
+                    .context("truncated chunk offset box")?;
+                let count = usize::try_from(u32::from_be_bytes(count.try_into().unwrap())).context("too many chunk offsets")?;
+                let entries = child.body.start + 8;
+                let end = count
+                    .checked_mul(entry_size)
+                    .and_then(|size| entries.checked_add(size))
+                    .filter(|&end| end <= child.body.end)
+                    .context("truncated chunk offset box")?;
+                for entry in data[entries..end].chunks_exact_mut(entry_size) {
+                    if entry_size == 4 {
+                        let offset = u32::from_be_bytes(entry.try_into().unwrap()) as u64;
+                        if moved.contains(&offset) {
+                            let offset = u32::try_from(offset + shift).context("chunk offset overflows stco")?;
+                            entry.copy_from_slice(&offset.to_be_bytes());
+                        }
+                    } else {
+                        let offset = u64::from_be_bytes(entry.try_into().unwrap());
+                        if moved.contains(&offset) {
+                            let offset = offset.checked_add(shift).context("chunk offset overflows co64")?;
+                            entry.copy_from_slice(&offset.to_be_bytes());
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Read the top-level `moov` box, skipping over the others
+pub(crate) fn read_moov(mut reader: impl Read + Seek) -> Result<Vec<u8>> {
+    let mut position = reader.stream_position()?;
+    loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header).context("no moov box")?;
+        let (size, header_size) = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+            0 => bail!("no moov box"),
+            1 => {
+                let mut size = [0; 8];
+                reader.read_exact(&mut size)?;
+                (u64::from_be_bytes(size), 16)
+            }
+            size => (size as u64, 8),
+        };
+        if size < header_size {
+            bail!("invalid size {size} of box {}", String::from_utf8_lossy(&header[4..8]));
+        }
+        if &header[4..8] != b"moov" {
+            let skip = i64::try_from(size - header_size).context("box size overflows")?;
+            let next = reader.seek(SeekFrom::Current(skip))?;
+            // a box always moves forward, so that a crafted size cannot loop
+            if next <= position {
+                bail!("invalid size {size} of box {}", String::from_utf8_lossy(&header[4..8]));
+            }
+            position = next;
+            continue;
+        }
+        let mut moov = header.to_vec();
+        if header_size == 16 {
+            moov.extend_from_slice(&size.to_be_bytes());
+        }
+        reader.take(size - header_size).read_to_end(&mut moov)?;
+        if moov.len() as u64 != size {
+            bail!("truncated moov box");
+        }
+        return Ok(moov);
+    }
+}
+
+/// The `trak` boxes in a `moov` box
+pub(crate) fn tracks(moov: &[u8]) -> Result<Vec<Mp4Box>> {
+    let body = boxes(moov, 0..moov.len())?.swap_remove(0).body;
+    Ok(boxes(moov, body)?.into_iter().filter(|b| &b.kind == b"trak").collect())
+}
+
+/// The id of a track, from its `tkhd` box
+pub(crate) fn track_id(moov: &[u8], trak: &Mp4Box) -> Result<u32> {
+    let tkhd = boxes(moov, trak.body.clone())?
+        .into_iter()
+        .find(|b| &b.kind == b"tkhd")
+        .context("no tkhd box")?;
+    // version and flags, then the creation and modification times, 64-bit in version 1
+    let body = &moov[tkhd.body];
+    let offset = if body.first() == Some(&1) { 20 } else { 12 };
+    let id = body.get(offset..offset + 4).context("truncated tkhd box")?;
+    Ok(u32::from_be_bytes(id.try_into().unwrap()))
+}
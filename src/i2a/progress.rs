@@ -0,0 +1,97 @@
+//! Progress report and cancellation of a single conversion
+//!
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Stages of a conversion, reported in this order. Stages that do not apply are skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum Stage {
+    /// Decode the HEIC, or the key frame of the video
+    Decode,
+    /// Encode the SDR base image to JPEG
+    BaseEncode,
+    /// Encode the gain map to JPEG
+    GainMap,
+    /// Encode the Ultra HDR JPEG
+    UltraHdrEncode,
+    /// Remux or append the video
+    Video,
+    /// Write the EXIF / XMP metadata
+    Metadata,
+}
+
+/// Receives the progress of a conversion. Called on the converting thread.
+pub trait ProgressObserver: Send + Sync {
+    fn stage(&self, _stage: Stage) {}
+    /// Number of input packets the video remux has processed so far
+    fn packets(&self, _packets: u64) {}
+}
+
+/// Cancels a conversion from another thread. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Progress observer and cancellation token of a conversion.
+/// The conversion checks the token between stages and between video packets, and fails if it is cancelled.
+#[derive(Clone, Default)]
+pub struct Progress {
+    pub observer: Option<Arc<dyn ProgressObserver>>,
+    pub cancellation: CancellationToken,
+}
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Progress")
+            .field("observer", &self.observer.is_some())
+            .field("cancellation", &self.cancellation)
+            .finish()
+    }
+}
+
+/// The error of a cancelled conversion, before it becomes [`super::ConvertError::Cancelled`]
+#[derive(Debug, thiserror::Error)]
+#[error("conversion cancelled")]
+pub(crate) struct Cancelled;
+
+impl Progress {
+    fn check(&self) -> anyhow::Result<()> {
+        match self.cancellation.is_cancelled() {
+            true => Err(Cancelled.into()),
+            false => Ok(()),
+        }
+    }
+
+    /// Report entering a stage, failing if cancelled
+    pub(crate) fn stage(&self, stage: Stage) -> anyhow::Result<()> {
+        self.check()?;
+        trace!(?stage, "conversion stage");
+        if let Some(observer) = self.observer.as_ref() {
+            observer.stage(stage);
+        }
+        Ok(())
+    }
+
+    /// Report the packet count of the video remux, failing if cancelled
+    pub(crate) fn packets(&self, packets: u64) -> anyhow::Result<()> {
+        self.check()?;
+        if let Some(observer) = self.observer.as_ref() {
+            observer.packets(packets);
+        }
+        Ok(())
+    }
+}
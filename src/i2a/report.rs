@@ -0,0 +1,73 @@
+//! Summary of a finished conversion, e.g. for dashboards
+//!
+
+use std::time::Instant;
+
+use super::progress::Stage;
+
+/// What a conversion did. Serializes to JSON with `serde_json`.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[non_exhaustive]
+pub struct ConvertReport {
+    /// Whether the image is an Apple HDR photo, converted to Ultra HDR
+    pub hdr: bool,
+    /// HDR headroom of the image, as a linear ratio
+    pub headroom: Option<f32>,
+    pub gain_map_width: Option<u32>,
+    pub gain_map_height: Option<u32>,
+    /// Size of the SDR base JPEG
+    pub base_image_bytes: u64,
+    /// Size of the gain map JPEG
+    pub gain_map_bytes: Option<u64>,
+    /// ffmpeg codec name of the source audio, e.g. "aac". None if the video has no audio.
+    pub audio_codec: Option<String>,
+    pub audio_transcoded: bool,
+    /// `MicroVideoOffset`: size of the appended video, i.e. its offset from the end of the file
+    pub video_offset: u64,
+    /// `MicroVideoPresentationTimestampUs`: time of the key photo in the appended video
+    pub presentation_timestamp_us: i64,
+    /// Time spent in each stage, in the order they were entered
+    pub stages: Vec<StageDuration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct StageDuration {
+    pub stage: Stage,
+    pub seconds: f64,
+}
+
+/// Collects the report while a conversion runs. Each run has its own.
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    report: ConvertReport,
+    stage: Option<(Stage, Instant)>,
+}
+
+impl Recorder {
+    pub(crate) fn update(&mut self, update: impl FnOnce(&mut ConvertReport)) {
+        update(&mut self.report);
+    }
+
+    /// End the current stage, and start `stage`
+    pub(crate) fn stage(&mut self, stage: Stage) {
+        if let Some((previous, start)) = self.stage.replace((stage, Instant::now())) {
+            self.add(previous, start);
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> ConvertReport {
+        if let Some((stage, start)) = self.stage.take() {
+            self.add(stage, start);
+        }
+        self.report
+    }
+
+    /// A stage entered more than once (e.g. `Metadata`) adds up
+    fn add(&mut self, stage: Stage, start: Instant) {
+        let seconds = start.elapsed().as_secs_f64();
+        match self.report.stages.iter_mut().find(|duration| duration.stage == stage) {
+            Some(duration) => duration.seconds += seconds,
+            None => self.report.stages.push(StageDuration { stage, seconds }),
+        }
+    }
+}
@@ -1,162 +1,655 @@
+use super::ConvertError;
 use anyhow::{Context, Result};
 use rsmpeg::{
-    avcodec::AVCodecContext,
-    avformat::{AVFormatContextInput, AVFormatContextOutput},
-    avutil::AVAudioFifo,
+    avcodec::{AVCodec, AVCodecContext, AVCodecParameters, AVCodecRef, AVPacket},
+    avfilter::{AVFilter, AVFilterGraph, AVFilterInOut},
+    avformat::{AVFormatContextInput, AVFormatContextOutput, AVIOContextContainer, AVIOContextCustom},
+    avutil::{AVAudioFifo, AVChannelLayout, AVDictionary, AVDictionaryRef, AVFrame, AVMem, AVPixFmtDescriptorRef, AVRational},
     swresample::SwrContext,
+    UnsafeDerefMut,
 };
-use std::{ffi::CString, path::Path};
+use std::{
+    ffi::CString,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// In Apple Live Photos, the key photo is taken about 1.5s after the start of the video.
+/// Used when the video has no still image time, see [`VideoUtils::get_still_image_time_us`].
+pub const LIVE_PHOTO_KEY_PHOTO_US: i64 = 1_500_000;
+
+/// A frame of the video, to be used as the key photo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KeyFrame {
+    /// The last frame not after this time (us), or the first frame if the video starts later
+    Timestamp(i64),
+    /// The n-th frame in presentation order, starting from 0. The last frame if the video is shorter.
+    Index(usize),
+}
+
+impl Default for KeyFrame {
+    fn default() -> Self {
+        Self::Timestamp(LIVE_PHOTO_KEY_PHOTO_US)
+    }
+}
+
+/// How to trim the video around the key photo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VideoTrim {
+    /// Keep `before` the key photo and `after` the key photo
+    Around { before: Duration, after: Duration },
+    /// Keep at most this duration, centered on the key photo when possible
+    MaxDuration(Duration),
+}
+
+impl VideoTrim {
+    /// Resolve to a range in a video of `duration_us`, whose key photo is at `key_photo_us`
+    pub fn range(&self, duration_us: i64, key_photo_us: i64) -> TimeRange {
+        let key_photo_us = key_photo_us.clamp(0, duration_us);
+        let (start_us, end_us) = match *self {
+            Self::Around { before, after } => (key_photo_us - before.as_micros() as i64, key_photo_us + after.as_micros() as i64),
+            Self::MaxDuration(max) => {
+                let max = (max.as_micros() as i64).min(duration_us);
+                let start_us = (key_photo_us - max / 2).clamp(0, duration_us - max);
+                (start_us, start_us + max)
+            }
+        };
+        TimeRange {
+            start_us: start_us.max(0),
+            end_us: end_us.min(duration_us),
+        }
+    }
+}
+
+/// Where a video is read from
+#[derive(Debug, Clone, Copy)]
+pub enum VideoInput<'a> {
+    File(&'a Path),
+    /// Shared with the demuxers, which are opened a few times per conversion
+    Memory(&'a Arc<[u8]>),
+}
+
+impl VideoInput<'_> {
+    /// None for a video in memory
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::File(path) => Some(path),
+            Self::Memory(_) => None,
+        }
+    }
+
+    fn error(&self) -> impl FnOnce(anyhow::Error) -> ConvertError + '_ {
+        move |error| {
+            ConvertError::classify(error, |source| ConvertError::Video {
+                path: self.path().map(Path::to_path_buf),
+                source,
+            })
+        }
+    }
+}
+
+impl<'a> From<&'a Path> for VideoInput<'a> {
+    fn from(path: &'a Path) -> Self {
+        Self::File(path)
+    }
+}
+
+/// Where a video is written to
+#[derive(Debug, Clone, Copy)]
+pub enum VideoOutput<'a> {
+    File(&'a Path),
+    /// Returned in [`ConvertedVideo::data`], with the `moov` atom at the front like a file output
+    Memory,
+}
+
+impl<'a> From<&'a Path> for VideoOutput<'a> {
+    fn from(path: &'a Path) -> Self {
+        Self::File(path)
+    }
+}
+
+/// Result of a video conversion
+#[derive(Debug)]
+pub struct ConvertedVideo {
+    /// The range of the input that is kept in the output
+    pub range: TimeRange,
+    /// The output video, if written to [`VideoOutput::Memory`]
+    pub data: Option<Vec<u8>>,
+    pub video_transcoded: bool,
+    /// ffmpeg codec name of the input audio. None if there is no audio.
+    pub audio_codec: Option<String>,
+    pub audio_transcoded: bool,
+}
+
+/// A range of the input video timeline, in microseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start_us: i64,
+    pub end_us: i64,
+}
+
+/// What to do with a rotated video, i.e. one with a display matrix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum VideoRotation {
+    /// Keep the rotation in the display matrix of the output, snapped to a multiple of 90 degrees
+    #[default]
+    Keep,
+    /// Rotate the pixels by re-encoding the video, and drop the display matrix
+    Bake,
+}
+
+/// What to do with HDR video, e.g. Dolby Vision profile 8.4 / HLG from iPhone 12 and later
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum HdrPolicy {
+    /// Copy the video as is
+    #[default]
+    Keep,
+    /// Remove the Dolby Vision configuration and RPU, leaving the backward compatible base layer (e.g. plain HLG)
+    StripDolbyVision,
+    /// Tone-map to SDR BT.709 by re-encoding. Needs ffmpeg built with libzimg (the `zscale` filter).
+    ToneMapSdr,
+}
+
+/// HDR format of a video stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VideoHdr {
+    /// Profile from the Dolby Vision configuration record, e.g. 8
+    pub dolby_vision_profile: Option<u8>,
+    /// Base layer signal compatibility id from the Dolby Vision configuration record, e.g. 4 for HLG
+    pub dolby_vision_compatibility_id: Option<u8>,
+    /// HLG (ARIB STD-B67) transfer characteristics
+    pub hlg: bool,
+    /// PQ (SMPTE ST 2084) transfer characteristics
+    pub pq: bool,
+}
+
+impl VideoHdr {
+    fn from_codecpar(codecpar: &AVCodecParameters) -> Self {
+        let side_data = unsafe {
+            rsmpeg::ffi::av_packet_side_data_get(
+                codecpar.coded_side_data,
+                codecpar.nb_coded_side_data,
+                rsmpeg::ffi::AV_PKT_DATA_DOVI_CONF,
+            )
+        };
+        let dovi =
+            if side_data.is_null() || unsafe { (*side_data).size } < std::mem::size_of::<rsmpeg::ffi::AVDOVIDecoderConfigurationRecord>() {
+                None
+            } else {
+                Some(unsafe { *((*side_data).data as *const rsmpeg::ffi::AVDOVIDecoderConfigurationRecord) })
+            };
+        Self {
+            dolby_vision_profile: dovi.map(|dovi| dovi.dv_profile),
+            dolby_vision_compatibility_id: dovi.map(|dovi| dovi.dv_bl_signal_compatibility_id),
+            hlg: codecpar.color_trc == rsmpeg::ffi::AVCOL_TRC_ARIB_STD_B67,
+            pq: codecpar.color_trc == rsmpeg::ffi::AVCOL_TRC_SMPTE2084,
+        }
+    }
+
+    pub fn is_hdr(&self) -> bool {
+        self.dolby_vision_profile.is_some() || self.hlg || self.pq
+    }
+}
+
+/// How to re-encode the video stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct VideoEncodeOptions {
+    /// ffmpeg encoder name, e.g. "libx264". None picks the first available H.264 encoder.
+    pub encoder: Option<String>,
+    pub rate_control: RateControl,
+    /// Scale down so that the longer edge is at most this many pixels
+    pub max_size: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RateControl {
+    /// Constant rate factor, for encoders with a `crf` option (libx264, ...). Lower is better.
+    Crf(u8),
+    /// Average bit rate, in bits per second
+    BitRate(i64),
+}
+
+impl Default for VideoEncodeOptions {
+    fn default() -> Self {
+        Self {
+            encoder: None,
+            rate_control: RateControl::Crf(23),
+            max_size: None,
+        }
+    }
+}
+
+impl VideoEncodeOptions {
+    /// H.264 encoders to try, in order of preference
+    const H264_ENCODERS: [&'static str; 4] = ["libx264", "libopenh264", "h264_videotoolbox", "h264_mf"];
+
+    /// Why the options are out of range, if they are
+    pub(crate) fn check(&self) -> Result<(), String> {
+        match (self.max_size, self.rate_control) {
+            (Some(max_size), _) if max_size < 2 => Err(format!("video max_size must be at least 2, got {max_size}")),
+            (_, RateControl::Crf(crf)) if crf > 51 => Err(format!("video crf must be in [0, 51], got {crf}")),
+            (_, RateControl::BitRate(bit_rate)) if bit_rate <= 0 => Err(format!("video bit rate must be positive, got {bit_rate}")),
+            _ => Ok(()),
+        }
+    }
+
+    fn find_encoder(&self) -> Result<AVCodecRef<'static>> {
+        if let Some(encoder) = self.encoder.as_deref() {
+            let name = CString::new(encoder)?;
+            return AVCodec::find_encoder_by_name(&name).with_context(|| format!("No encoder named {encoder}"));
+        }
+        Self::H264_ENCODERS
+            .iter()
+            .find_map(|name| AVCodec::find_encoder_by_name(&CString::new(*name).unwrap()))
+            .context("No H.264 encoder available")
+    }
+
+    /// Output size, fitted in `max_size` and rounded to even numbers for chroma subsampling
+    fn output_size(&self, width: i32, height: i32) -> (i32, i32) {
+        let scale = match self.max_size {
+            Some(max_size) if width.max(height) > max_size as i32 => max_size as f64 / width.max(height) as f64,
+            _ => 1.0,
+        };
+        let even = |x: i32| ((x as f64 * scale / 2.0).round() as i32 * 2).max(2);
+        (even(width), even(height))
+    }
+}
+
+/// Channel layout of the transcoded audio
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum AudioChannels {
+    /// Same layout as the input, if the encoder supports it
+    #[default]
+    Keep,
+    Mono,
+    Stereo,
+}
 
-pub struct VideoAudioEncodeRequest<'a> {
-    pub input: &'a Path,
-    pub output: &'a Path,
+impl AudioChannels {
+    /// Output layout for the given input layout
+    fn layout(&self, input: &AVChannelLayout) -> AVChannelLayout {
+        let nb_channels = match self {
+            AudioChannels::Keep => {
+                let mut layout = AVChannelLayout::from_nb_channels(input.nb_channels);
+                layout.copy(input);
+                return layout;
+            }
+            AudioChannels::Mono => 1,
+            AudioChannels::Stereo => 2,
+        };
+        AVChannelLayout::from_nb_channels(nb_channels)
+    }
+}
+
+/// How to transcode the audio stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AudioOptions {
+    /// ffmpeg encoder name, e.g. "aac", or "libopus" when ffmpeg is built with it
+    pub encoder: String,
+    /// Bit rate, in bits per second
     pub bit_rate: i64,
-    pub encoder: &'static str,
+    /// Resample to this rate, in Hz. None keeps the input rate, if the encoder supports it.
+    pub sample_rate: Option<i32>,
+    pub channels: AudioChannels,
+    /// Input audio codecs that are copied untouched instead of transcoded
+    pub passthrough_codecs: Vec<String>,
+}
+
+impl Default for AudioOptions {
+    fn default() -> Self {
+        Self {
+            encoder: "aac".to_string(),
+            bit_rate: 128 << 10,
+            sample_rate: None,
+            channels: AudioChannels::default(),
+            passthrough_codecs: vec!["aac".to_string(), "ac3".to_string()],
+        }
+    }
 }
 
-impl VideoAudioEncodeRequest<'_> {
+impl AudioOptions {
+    /// Why the options are out of range, if they are
+    pub(crate) fn check(&self) -> Result<(), String> {
+        if self.bit_rate <= 0 {
+            return Err(format!("audio bit rate must be positive, got {}", self.bit_rate));
+        }
+        match self.sample_rate {
+            Some(sample_rate) if sample_rate <= 0 => Err(format!("audio sample rate must be positive, got {sample_rate}")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether an input audio stream of `codec` is copied untouched
+    pub fn passthrough(&self, codec: &str) -> bool {
+        self.passthrough_codecs.iter().any(|passthrough| passthrough == codec)
+    }
+
+    fn find_encoder(&self) -> Result<AVCodecRef<'static>> {
+        let name = CString::new(self.encoder.as_str())?;
+        AVCodec::find_encoder_by_name(&name).with_context(|| format!("No encoder named {}", self.encoder))
+    }
+
+    /// The wanted sample rate if the encoder supports it, otherwise the closest higher (or highest) one
+    fn sample_rate(&self, codec: &AVCodec, input_sample_rate: i32) -> i32 {
+        let wanted = self.sample_rate.unwrap_or(input_sample_rate);
+        let supported = codec.supported_samplerates;
+        if supported.is_null() {
+            return wanted;
+        }
+        // the list is terminated by 0
+        let mut rates = vec![];
+        for i in 0.. {
+            let rate = unsafe { *supported.add(i) };
+            if rate == 0 {
+                break;
+            }
+            rates.push(rate);
+        }
+        if rates.contains(&wanted) {
+            return wanted;
+        }
+        let fallback = rates
+            .iter()
+            .filter(|&&rate| rate > wanted)
+            .min()
+            .or(rates.iter().max())
+            .copied()
+            .unwrap_or(wanted);
+        warn!(%wanted, %fallback, "encoder does not support the sample rate");
+        fallback
+    }
+}
+
+/// What to do with a stream of the input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamAction<T> {
+    /// Copy the packets without re-encoding
+    Copy,
+    /// Decode and re-encode with these options
+    Transcode(T),
+    /// Leave the stream out of the output
+    Drop,
+}
+
+/// Copy, transcode or drop the best video and audio streams of the input into a new MP4.
+/// Other tracks, such as Apple timed metadata, are dropped.
+pub struct VideoRemuxRequest<'a> {
+    pub input: VideoInput<'a>,
+    pub output: VideoOutput<'a>,
+    /// Only keep this range of the input. A copied video starts at the keyframe at or before the start,
+    /// see [`ConvertedVideo::range`]. A transcoded video starts exactly.
+    pub trim: Option<TimeRange>,
+    /// Re-encode a copied video with the default options if the trim start is not on a keyframe, to start exactly
+    pub exact_trim: bool,
+    /// The video cannot be dropped. Baking the rotation or tone mapping transcodes a copied video with the default options.
+    pub video: StreamAction<VideoEncodeOptions>,
+    /// Ignored if the input has no audio. Codecs in [`AudioOptions::passthrough_codecs`] are copied instead of transcoded.
+    pub audio: StreamAction<AudioOptions>,
+    pub rotation: VideoRotation,
+    pub hdr: HdrPolicy,
+    /// Receives the packet count, and cancels between packets
+    pub progress: super::progress::Progress,
+    /// Which container and stream metadata keys are copied
+    pub metadata: crate::utils::MetadataPolicy,
+    /// Rewrite the creation dates
+    pub time_shift: Option<crate::utils::TimeShift>,
+}
+
+/// The actions of a [`VideoRemuxRequest`], resolved against its input
+#[derive(Debug)]
+struct RemuxPlan {
+    /// Set if the video is transcoded
+    video: Option<VideoEncodeOptions>,
+    tone_map: bool,
+    rotation_cw: i32,
+    strip_dolby_vision: bool,
+    /// Never `Transcode` for a passthrough codec. None if the input has no audio.
+    audio: Option<StreamAction<AudioOptions>>,
+    audio_codec: Option<String>,
+}
+
+impl VideoRemuxRequest<'_> {
     pub fn mute_ffmpeg_log() {
         unsafe { rsmpeg::ffi::av_log_set_level(rsmpeg::ffi::AV_LOG_ERROR as i32) };
     }
 
-    pub fn execute(&self) -> Result<()> {
+    /// Whether the input cannot be used as is, i.e. [`Self::execute`] would change more than the container
+    pub fn needs_remux(&self) -> Result<bool, ConvertError> {
+        self.remux_needed().map_err(self.input.error())
+    }
+
+    fn remux_needed(&self) -> Result<bool> {
+        if self.trim.is_some() || self.metadata != crate::utils::MetadataPolicy::KeepAll || self.time_shift.is_some() {
+            return Ok(true);
+        }
+        let i_fmt_ctx = input_format_context(self.input).context("create input format context failed")?;
+        let plan = self.plan(&i_fmt_ctx)?;
+        debug!(?plan, "remux planned");
+        Ok(plan.video.is_some()
+            || plan.rotation_cw != 0
+            || plan.strip_dolby_vision
+            || plan.audio.is_some_and(|audio| audio != StreamAction::Copy))
+    }
+
+    fn plan(&self, i_fmt_ctx: &AVFormatContextInput) -> Result<RemuxPlan> {
+        let rotation_cw = best_video_rotation(i_fmt_ctx)?;
+        let hdr = best_video_hdr(i_fmt_ctx)?;
+        let tone_map = self.hdr == HdrPolicy::ToneMapSdr && hdr.is_hdr();
+        let video = match &self.video {
+            StreamAction::Transcode(options) => Some(options.clone()),
+            StreamAction::Copy if tone_map || (self.rotation == VideoRotation::Bake && rotation_cw != 0) => {
+                Some(VideoEncodeOptions::default())
+            }
+            StreamAction::Copy => None,
+            StreamAction::Drop => anyhow::bail!("Dropping the video stream is not supported"),
+        };
+        let audio_codec = i_fmt_ctx
+            .find_best_stream(rsmpeg::ffi::AVMEDIA_TYPE_AUDIO)
+            .context("Find audio stream failed")?
+            .map(|(_, codec)| codec.name().to_string_lossy().into_owned());
+        let audio = audio_codec.as_deref().map(|codec| match &self.audio {
+            StreamAction::Transcode(options) if options.passthrough(codec) => StreamAction::Copy,
+            action => action.clone(),
+        });
+        Ok(RemuxPlan {
+            strip_dolby_vision: video.is_none() && self.hdr == HdrPolicy::StripDolbyVision && hdr.dolby_vision_profile.is_some(),
+            video,
+            tone_map,
+            rotation_cw,
+            audio,
+            audio_codec,
+        })
+    }
+
+    pub fn execute(&self) -> Result<ConvertedVideo, ConvertError> {
+        self.remux().map_err(self.input.error())
+    }
+
+    fn remux(&self) -> Result<ConvertedVideo> {
         // 1.a open input
-        let mut i_fmt_ctx = self.input_format_context().context("create input format context failed")?;
+        let mut i_fmt_ctx = input_format_context(self.input).context("create input format context failed")?;
 
         // 1.b open output
-        let mut o_fmt_ctx = self.output_format_context().context("create output format context failed")?;
-
-        // 2.a configurations: video
-        let (input_video_idx, output_video_idx) =
-            Self::get_video_stream_index(&i_fmt_ctx, &mut o_fmt_ctx).context("get video stream index failed")?;
-        debug!(%input_video_idx, %output_video_idx, "video configured");
-
-        // 2.b configurations: audio
-        let mut audio = self
-            .get_audio_configure(&i_fmt_ctx, &mut o_fmt_ctx)
-            .context("get audio configure failed")?;
-        debug!(
-            input_audio_idx = audio.input_stream_index,
-            output_audio_idx = audio.output_stream_index,
-            "audio configured"
-        );
+        let (mut o_fmt_ctx, memory) = output_format_context(self.output).context("create output format context failed")?;
+
+        let mut plan = self.plan(&i_fmt_ctx)?;
+        debug!(?plan, "remux planned");
+        let audio_transcoded = matches!(plan.audio, Some(StreamAction::Transcode(_)));
+
+        // 2.a configurations: trim
+        let mut cut = match self.trim {
+            Some(trim) => {
+                let (video_idx, _) = i_fmt_ctx
+                    .find_best_stream(rsmpeg::ffi::AVMEDIA_TYPE_VIDEO)
+                    .context("Find video stream failed")?
+                    .context("No video stream found")?;
+                Cut::find(self.input, video_idx, trim).context("find trim keyframe failed")?
+            }
+            None => Cut::full(),
+        };
+        if self.exact_trim && !cut.is_exact() && plan.video.is_none() {
+            // a copy can only start at a keyframe
+            debug!("trim start is not on a keyframe, re-encode the video");
+            plan.video = Some(VideoEncodeOptions::default());
+        }
+        let video_transcoded = plan.video.is_some();
+
+        // 2.b configurations: video
+        let mut video = match plan.video.as_ref() {
+            Some(options) => {
+                let mut video = VideoConfigure::new(&i_fmt_ctx, &mut o_fmt_ctx, options, self.rotation, plan.tone_map)
+                    .context("get video configure failed")?;
+                if self.trim.is_some() {
+                    // decoding starts at the keyframe, and the frames before the requested start are dropped
+                    cut.start_exactly();
+                    video.start_pts = 0;
+                }
+                Some(video)
+            }
+            None => None,
+        };
+        let (input_video_idx, output_video_idx, strip_dolby_vision) = match video.as_ref() {
+            Some(video) => (video.input_stream_index, video.output_stream_index, None),
+            None => copy_video_stream(&i_fmt_ctx, &mut o_fmt_ctx, self.hdr).context("get video stream index failed")?,
+        };
+        debug!(%input_video_idx, %output_video_idx, transcode = video.is_some(), "video configured");
+
+        // 2.c configurations: audio
+        let mut audio = None;
+        let mut audio_copy = None;
+        match plan.audio {
+            Some(StreamAction::Transcode(options)) => {
+                audio = Some(AudioConfigure::new(&i_fmt_ctx, &mut o_fmt_ctx, &options).context("get audio configure failed")?);
+            }
+            Some(StreamAction::Copy) => {
+                audio_copy = Some(copy_audio_stream(&i_fmt_ctx, &mut o_fmt_ctx).context("get audio stream index failed")?);
+            }
+            Some(StreamAction::Drop) | None => {}
+        }
+        let audio_idx = match audio.as_ref() {
+            Some(audio) => Some((audio.input_stream_index, audio.output_stream_index)),
+            None => audio_copy,
+        };
+        debug!(?audio_idx, transcode = audio.is_some(), "audio configured");
+        let streams = [Some((input_video_idx, output_video_idx)), audio_idx];
+        let streams = streams.into_iter().flatten().collect::<Vec<_>>();
+        copy_metadata(&i_fmt_ctx, &mut o_fmt_ctx, &streams, self.metadata, self.time_shift);
 
         // 3. open and write header
-        let mut output_options = None;
+        let mut output_options = Some(muxer_options(memory.is_none()));
         o_fmt_ctx
             .write_header(&mut output_options)
             .context("output context write header failed")?;
 
-        // 4. copy packets
-        let mut fifo = AVAudioFifo::new(
-            audio.output_codec_context.sample_fmt,
-            audio.output_codec_context.ch_layout().nb_channels,
-            1,
-        );
-        let frame_size = audio.output_codec_context.frame_size as usize;
-        let mut pts: i64 = 0;
-        debug!(
-            "ia_frame_size = {}, oa_frame_size = {}",
-            audio.input_codec_context.frame_size, audio.output_codec_context.frame_size
-        );
-
+        // 4. copy or transcode packets
+        let mut packets = 0;
         while let Some(mut packet) = i_fmt_ctx.read_packet().context("read packet failed")? {
-            if packet.stream_index == input_video_idx as i32 {
-                packet.rescale_ts(
-                    i_fmt_ctx.streams()[input_video_idx].time_base,
-                    o_fmt_ctx.streams()[output_video_idx].time_base,
-                );
-                packet.set_stream_index(output_video_idx as i32);
-                o_fmt_ctx.write_frame(&mut packet).context("o_fmt_ctx write frame failed")?;
-                continue;
-            }
-            if packet.stream_index == audio.input_stream_index as i32 {
-                audio
-                    .input_codec_context
-                    .send_packet(Some(&packet))
-                    .context("Send packet to input audio codec context failed")?;
-                while let Ok(frame) = audio.input_codec_context.receive_frame() {
-                    let mut converted_frame = audio.new_frame();
-                    audio
-                        .resampler
-                        .convert_frame(Some(&frame), &mut converted_frame)
-                        .context("convert frame failed")?;
-                    converted_frame.set_pts(pts);
-                    pts += converted_frame.nb_samples as i64;
-
-                    unsafe { fifo.write(converted_frame.data.as_ptr(), converted_frame.nb_samples) }?;
-
-                    while fifo.size() as usize >= frame_size {
-                        let mut new_frame = audio.new_frame();
-                        new_frame.set_nb_samples(frame_size as i32);
-                        new_frame.set_pts(pts - fifo.size() as i64);
-                        new_frame.alloc_buffer()?;
-                        unsafe { fifo.read(new_frame.data.as_ptr(), new_frame.nb_samples) }?;
-
-                        audio
-                            .output_codec_context
-                            .send_frame(Some(&new_frame))
-                            .context("send converted frame to output codec context failed")?;
-                        audio.flush_output(&i_fmt_ctx, &mut o_fmt_ctx)?;
-                    }
+            packets += 1;
+            self.progress.packets(packets)?;
+            let input_idx = packet.stream_index as usize;
+            let time_base = i_fmt_ctx.streams()[input_idx].time_base;
+            if input_idx == input_video_idx {
+                if !cut.keep_video(&packet, time_base) {
+                    continue;
+                }
+                cut.shift(&mut packet, time_base);
+                if let Some(video) = video.as_mut() {
+                    video.transcode(Some(&packet), &mut o_fmt_ctx).context("transcode video failed")?;
+                    continue;
                 }
+                if let Some(strip) = strip_dolby_vision.as_ref() {
+                    strip.apply(&mut packet).context("strip Dolby Vision failed")?;
+                }
+                write_copied_packet(&mut o_fmt_ctx, packet, time_base, output_video_idx)?;
+            } else if audio_idx.is_some_and(|(input_audio_idx, _)| input_audio_idx == input_idx) {
+                if !cut.keep_audio(&packet, time_base) {
+                    continue;
+                }
+                if let Some(audio) = audio.as_mut() {
+                    audio.transcode(Some(&packet), &mut o_fmt_ctx).context("transcode audio failed")?;
+                    continue;
+                }
+                cut.shift(&mut packet, time_base);
+                let (_, output_audio_idx) = audio_idx.unwrap();
+                write_copied_packet(&mut o_fmt_ctx, packet, time_base, output_audio_idx)?;
             }
         }
 
-        // 处理 FIFO 中剩余的数据
-        while fifo.size() > 0 {
-            debug!("flushing tail packets, size={}", fifo.size());
-            let samples = std::cmp::min(fifo.size(), frame_size as i32);
-            let mut new_frame = audio.new_frame();
-            new_frame.set_nb_samples(samples as i32);
-            new_frame.set_pts(pts - samples as i64);
-            new_frame.alloc_buffer()?;
-            unsafe { fifo.read(new_frame.data.as_ptr(), new_frame.nb_samples) }?;
-
-            audio
-                .output_codec_context
-                .send_frame(Some(&new_frame))
-                .context("send remaining frame to output codec context failed")?;
-            audio.flush_output(&i_fmt_ctx, &mut o_fmt_ctx)?;
+        // 5. flush transcoders and write trailer
+        if let Some(audio) = audio.as_mut() {
+            audio.transcode(None, &mut o_fmt_ctx).context("flush audio failed")?;
+        }
+        if let Some(video) = video.as_mut() {
+            video.transcode(None, &mut o_fmt_ctx).context("flush video failed")?;
         }
-
-        // 清空输出编解码器缓冲区
-        audio.output_codec_context.send_frame(None)?;
-        audio.flush_output(&i_fmt_ctx, &mut o_fmt_ctx)?;
-
-        // 5. write trailer
         o_fmt_ctx.write_trailer().context("write trailer failed")?;
 
-        Ok(())
+        Ok(ConvertedVideo {
+            range: cut.range(i_fmt_ctx.duration),
+            data: finish_output(o_fmt_ctx, memory)?,
+            video_transcoded,
+            audio_transcoded,
+            audio_codec: plan.audio_codec,
+        })
     }
+}
 
-    fn input_format_context(&self) -> Result<AVFormatContextInput> {
-        let input = self.input.to_str().context("input path to_str failed")?;
-        let input = CString::new(input)?;
-        let mut input_options = None;
-        let format_context = rsmpeg::avformat::AVFormatContextInput::open(&input, None, &mut input_options)?;
-        Ok(format_context)
-    }
+/// Write a packet of a copied stream, in the output stream time base
+fn write_copied_packet(
+    o_fmt_ctx: &mut AVFormatContextOutput,
+    mut packet: AVPacket,
+    time_base: AVRational,
+    output_idx: usize,
+) -> Result<()> {
+    packet.rescale_ts(time_base, o_fmt_ctx.streams()[output_idx].time_base);
+    packet.set_stream_index(output_idx as i32);
+    packet.set_pos(-1);
+    o_fmt_ctx
+        .interleaved_write_frame(&mut packet)
+        .context("o_fmt_ctx write frame failed")
+}
 
-    fn output_format_context(&self) -> Result<AVFormatContextOutput> {
-        let output = self.output.to_str().context("output path to_str failed")?;
-        let output = CString::new(output)?;
-        let format_context = rsmpeg::avformat::AVFormatContextOutput::create(&output, None)?;
-        Ok(format_context)
+/// The wanted layout if the encoder supports it, otherwise the default layout with at most 2 channels
+fn encoder_channel_layout(codec: &AVCodec, wanted: AVChannelLayout) -> AVChannelLayout {
+    let mut supported = codec.ch_layouts;
+    if supported.is_null() {
+        return wanted;
     }
-
-    fn get_video_stream_index(i_fmt_ctx: &AVFormatContextInput, o_fmt_ctx: &mut AVFormatContextOutput) -> Result<(usize, usize)> {
-        let (input_video_idx, _) = i_fmt_ctx
-            .find_best_stream(rsmpeg::ffi::AVMEDIA_TYPE_VIDEO)
-            .context("Find video stream failed")?
-            .context("No video stream found")?;
-        let input_video = &i_fmt_ctx.streams()[input_video_idx];
-        let mut output_video = o_fmt_ctx.new_stream();
-        output_video.codecpar_mut().copy(&input_video.codecpar());
-        Ok((input_video_idx, output_video.index as usize))
+    // the list is terminated by a zeroed layout
+    while unsafe { (*supported).nb_channels } != 0 {
+        if unsafe { rsmpeg::ffi::av_channel_layout_compare(supported, wanted.as_ptr()) } == 0 {
+            return wanted;
+        }
+        supported = unsafe { supported.add(1) };
     }
+    let fallback = AVChannelLayout::from_nb_channels(wanted.nb_channels.min(2));
+    warn!(wanted = ?wanted.describe(), fallback = ?fallback.describe(), "encoder does not support the channel layout");
+    fallback
+}
+
+struct AudioConfigure {
+    pub input_stream_index: usize,
+    pub output_stream_index: usize,
+    pub input_codec_context: AVCodecContext,
+    pub output_codec_context: AVCodecContext,
+    pub resampler: SwrContext,
+    pub fifo: AVAudioFifo,
+    /// Samples written to the fifo so far, in the encoder time base
+    pub pts: i64,
+}
 
-    fn get_audio_configure(&self, i_fmt_ctx: &AVFormatContextInput, o_fmt_ctx: &mut AVFormatContextOutput) -> Result<AudioConfigure> {
+impl AudioConfigure {
+    pub fn new(i_fmt_ctx: &AVFormatContextInput, o_fmt_ctx: &mut AVFormatContextOutput, options: &AudioOptions) -> Result<Self> {
         // 1. get input audio index and codec
         let (i_idx, i_codec) = i_fmt_ctx
             .find_best_stream(rsmpeg::ffi::AVMEDIA_TYPE_AUDIO)
@@ -166,24 +659,30 @@ impl VideoAudioEncodeRequest<'_> {
         // 2. create input codec context
         let mut i_codec_ctx = rsmpeg::avcodec::AVCodecContext::new(&i_codec);
         i_codec_ctx.apply_codecpar(&i_stream.codecpar())?;
-        i_codec_ctx.set_ch_layout(*rsmpeg::avutil::AVChannelLayout::from_nb_channels(1)); // overwrite channel layout
+        if i_codec_ctx.ch_layout().order == rsmpeg::ffi::AV_CHANNEL_ORDER_UNSPEC {
+            // only the number of channels is known, assume the default layout
+            let nb_channels = i_codec_ctx.ch_layout().nb_channels;
+            i_codec_ctx.set_ch_layout(AVChannelLayout::from_nb_channels(nb_channels).into_inner());
+        }
         i_codec_ctx.set_pkt_timebase(i_stream.time_base);
         i_codec_ctx.set_time_base(i_stream.time_base);
         i_codec_ctx.open(None).context("input audio codec context open failed")?;
-        debug!(%i_codec_ctx.sample_rate, %i_codec_ctx.bit_rate, %i_codec_ctx.frame_size, "input audio codec");
+        let i_layout = i_codec_ctx.ch_layout().describe()?;
+        debug!(%i_codec_ctx.sample_rate, %i_codec_ctx.bit_rate, %i_codec_ctx.frame_size, ?i_layout, "input audio codec");
 
         // 3. create output audio stream
         let global_header = (o_fmt_ctx.flags & rsmpeg::ffi::AVFMT_GLOBALHEADER as i32) != 0;
         let mut o_stream = o_fmt_ctx.new_stream();
         let output_stream_index = o_stream.index as usize;
         // 4. create output audio codec context
-        let name = CString::new(self.encoder)?;
-        let o_codec = rsmpeg::avcodec::AVCodec::find_encoder_by_name(&name).context("No encoder found.")?;
+        let o_codec = options.find_encoder()?;
         debug!("Using encoder {:?}", o_codec.name());
         let mut o_codec_ctx = rsmpeg::avcodec::AVCodecContext::new(&o_codec);
-        o_codec_ctx.set_sample_rate(i_codec_ctx.sample_rate);
-        o_codec_ctx.set_bit_rate(self.bit_rate);
-        o_codec_ctx.set_ch_layout(*rsmpeg::avutil::AVChannelLayout::from_nb_channels(1));
+        // the resampler converts the sample rate and the channel layout
+        o_codec_ctx.set_sample_rate(options.sample_rate(&o_codec, i_codec_ctx.sample_rate));
+        o_codec_ctx.set_bit_rate(options.bit_rate);
+        let o_layout = encoder_channel_layout(&o_codec, options.channels.layout(&i_codec_ctx.ch_layout()));
+        o_codec_ctx.set_ch_layout(o_layout.into_inner());
         o_codec_ctx.set_sample_fmt(o_codec.sample_fmts().unwrap()[0]);
         // See https://github.com/larksuite/rsmpeg/issues/198
         // o_codec_ctx.set_frame_size(1024);
@@ -191,7 +690,7 @@ impl VideoAudioEncodeRequest<'_> {
         // o_codec_ctx.set_profile(rsmpeg::ffi::FF_PROFILE_AAC_LOW as i32);
         o_codec_ctx.set_pkt_timebase(rsmpeg::avutil::AVRational {
             num: 1,
-            den: i_codec_ctx.sample_rate,
+            den: o_codec_ctx.sample_rate,
         });
         o_codec_ctx.set_time_base(rsmpeg::avutil::AVRational {
             num: 1,
@@ -204,7 +703,8 @@ impl VideoAudioEncodeRequest<'_> {
 
         o_codec_ctx.open(None).context("output audio codec context open failed")?;
         o_stream.codecpar_mut().from_context(&o_codec_ctx);
-        debug!(%o_codec_ctx.sample_rate, %o_codec_ctx.bit_rate, %o_codec_ctx.frame_size, "output audio codec");
+        let o_layout = o_codec_ctx.ch_layout().describe()?;
+        debug!(%o_codec_ctx.sample_rate, %o_codec_ctx.bit_rate, %o_codec_ctx.frame_size, ?o_layout, "output audio codec");
 
         // 5. create resampler
         let mut resampler = rsmpeg::swresample::SwrContext::new(
@@ -217,25 +717,20 @@ impl VideoAudioEncodeRequest<'_> {
         )?;
         resampler.init()?;
 
-        Ok(AudioConfigure {
+        // 6. fifo, re-cutting resampled frames to the encoder frame size
+        let fifo = AVAudioFifo::new(o_codec_ctx.sample_fmt, o_codec_ctx.ch_layout().nb_channels, 1);
+
+        Ok(Self {
             input_stream_index: i_idx,
             output_stream_index,
             input_codec_context: i_codec_ctx,
             output_codec_context: o_codec_ctx,
             resampler,
+            fifo,
+            pts: 0,
         })
     }
-}
-
-struct AudioConfigure {
-    pub input_stream_index: usize,
-    pub output_stream_index: usize,
-    pub input_codec_context: AVCodecContext,
-    pub output_codec_context: AVCodecContext,
-    pub resampler: SwrContext,
-}
 
-impl AudioConfigure {
     pub fn new_frame(&self) -> rsmpeg::avutil::AVFrame {
         let mut frame = rsmpeg::avutil::AVFrame::new();
         frame.set_ch_layout(**self.output_codec_context.ch_layout());
@@ -244,24 +739,872 @@ impl AudioConfigure {
         frame
     }
 
-    pub fn flush_output(&mut self, i_fmt_ctx: &AVFormatContextInput, o_fmt_ctx: &mut AVFormatContextOutput) -> Result<()> {
+    /// Decode a packet (None to flush), resample, and encode the frames
+    pub fn transcode(&mut self, packet: Option<&AVPacket>, o_fmt_ctx: &mut AVFormatContextOutput) -> Result<()> {
+        self.input_codec_context
+            .send_packet(packet)
+            .context("Send packet to input audio codec context failed")?;
+        while let Ok(frame) = self.input_codec_context.receive_frame() {
+            let mut converted_frame = self.new_frame();
+            self.resampler
+                .convert_frame(Some(&frame), &mut converted_frame)
+                .context("convert frame failed")?;
+            self.write_fifo(&converted_frame)?;
+            self.encode_fifo(false, o_fmt_ctx)?;
+        }
+        if packet.is_none() {
+            // 取出重采样器中延迟的数据
+            loop {
+                let mut converted_frame = self.new_frame();
+                self.resampler
+                    .convert_frame(None, &mut converted_frame)
+                    .context("flush resampler failed")?;
+                if converted_frame.nb_samples == 0 {
+                    break;
+                }
+                self.write_fifo(&converted_frame)?;
+            }
+            // 处理 FIFO 中剩余的数据
+            self.encode_fifo(true, o_fmt_ctx)?;
+            // 清空输出编解码器缓冲区
+            self.output_codec_context.send_frame(None)?;
+            self.flush_output(o_fmt_ctx)?;
+        }
+        Ok(())
+    }
+
+    fn write_fifo(&mut self, frame: &AVFrame) -> Result<()> {
+        unsafe { self.fifo.write(frame.data.as_ptr(), frame.nb_samples) }?;
+        self.pts += frame.nb_samples as i64;
+        Ok(())
+    }
+
+    /// Encode frames of the encoder frame size from the fifo. With `flush`, also the remaining samples.
+    fn encode_fifo(&mut self, flush: bool, o_fmt_ctx: &mut AVFormatContextOutput) -> Result<()> {
+        let frame_size = match self.output_codec_context.frame_size {
+            // the encoder accepts any frame size
+            0 => 1024,
+            frame_size => frame_size,
+        };
+        while self.fifo.size() >= frame_size || (flush && self.fifo.size() > 0) {
+            let samples = self.fifo.size().min(frame_size);
+            let mut new_frame = self.new_frame();
+            new_frame.set_nb_samples(samples);
+            new_frame.set_pts(self.pts - self.fifo.size() as i64);
+            new_frame.alloc_buffer()?;
+            unsafe { self.fifo.read(new_frame.data.as_ptr(), new_frame.nb_samples) }?;
+
+            self.output_codec_context
+                .send_frame(Some(&new_frame))
+                .context("send converted frame to output codec context failed")?;
+            self.flush_output(o_fmt_ctx)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush_output(&mut self, o_fmt_ctx: &mut AVFormatContextOutput) -> Result<()> {
         while let Ok(mut packet) = self.output_codec_context.receive_packet() {
             packet.set_stream_index(self.output_stream_index as i32);
-            let from = i_fmt_ctx.streams()[self.input_stream_index].time_base;
+            // frames are counted in output samples, so packets are in the encoder time base
+            let from = self.output_codec_context.time_base;
             let to = o_fmt_ctx.streams()[self.output_stream_index].time_base;
             packet.rescale_ts(from, to);
-            o_fmt_ctx.write_frame(&mut packet).context("write frame failed")?;
+            o_fmt_ctx.interleaved_write_frame(&mut packet).context("write frame failed")?;
         }
         Ok(())
     }
 }
 
+struct VideoConfigure {
+    pub input_stream_index: usize,
+    pub output_stream_index: usize,
+    pub input_codec_context: AVCodecContext,
+    pub output_codec_context: AVCodecContext,
+    /// buffer "in" -> (tone map) -> scale -> (transpose) -> format -> buffersink "out"
+    pub filter_graph: AVFilterGraph,
+    /// Decoded frames before this pts (input time base) are dropped, e.g. before the start of a trim
+    pub start_pts: i64,
+}
+
+impl VideoConfigure {
+    /// HDR (HLG / PQ) to SDR BT.709: linearize, tone-map in float RGB, then back to BT.709
+    const TONE_MAP_FILTERS: &'static str = "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,\
+        tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv";
+
+    pub fn new(
+        i_fmt_ctx: &AVFormatContextInput,
+        o_fmt_ctx: &mut AVFormatContextOutput,
+        options: &VideoEncodeOptions,
+        rotation: VideoRotation,
+        tone_map: bool,
+    ) -> Result<Self> {
+        // 1. input video decoder
+        let (i_idx, i_codec) = i_fmt_ctx
+            .find_best_stream(rsmpeg::ffi::AVMEDIA_TYPE_VIDEO)
+            .context("Find video stream failed")?
+            .context("No video stream found")?;
+        let i_stream = &i_fmt_ctx.streams()[i_idx];
+        let mut i_codec_ctx = AVCodecContext::new(&i_codec);
+        i_codec_ctx.apply_codecpar(&i_stream.codecpar())?;
+        i_codec_ctx.set_pkt_timebase(i_stream.time_base);
+        i_codec_ctx.open(None).context("input video codec context open failed")?;
+        let rotation_cw = display_rotation(&i_stream.codecpar());
+        debug!(%i_codec_ctx.width, %i_codec_ctx.height, %i_codec_ctx.pix_fmt, %rotation_cw, "input video codec");
+        // transpose filter to bake the rotation into pixels
+        let transpose = match rotation {
+            VideoRotation::Bake => transpose_filter(rotation_cw),
+            VideoRotation::Keep => None,
+        };
+
+        // 2. output video encoder
+        let o_codec = options.find_encoder()?;
+        debug!("Using video encoder {:?}", o_codec.name());
+        let (width, height) = options.output_size(i_codec_ctx.width, i_codec_ctx.height);
+        let (o_width, o_height) = match transpose {
+            Some(_) if rotation_cw != 180 => (height, width),
+            _ => (width, height),
+        };
+        let pix_fmt = match o_codec.pix_fmts() {
+            Some(pix_fmts) if !pix_fmts.contains(&rsmpeg::ffi::AV_PIX_FMT_YUV420P) => pix_fmts[0],
+            _ => rsmpeg::ffi::AV_PIX_FMT_YUV420P,
+        };
+        let global_header = (o_fmt_ctx.flags & rsmpeg::ffi::AVFMT_GLOBALHEADER as i32) != 0;
+        let mut o_stream = o_fmt_ctx.new_stream();
+        let output_stream_index = o_stream.index as usize;
+        let mut o_codec_ctx = AVCodecContext::new(&o_codec);
+        o_codec_ctx.set_width(o_width);
+        o_codec_ctx.set_height(o_height);
+        o_codec_ctx.set_pix_fmt(pix_fmt);
+        o_codec_ctx.set_sample_aspect_ratio(i_codec_ctx.sample_aspect_ratio);
+        o_codec_ctx.set_time_base(i_stream.time_base);
+        if let Some(framerate) = i_stream.guess_framerate().filter(|r| r.num > 0) {
+            o_codec_ctx.set_framerate(framerate);
+        }
+        unsafe {
+            let o_codec_ctx = o_codec_ctx.deref_mut();
+            if tone_map {
+                o_codec_ctx.color_primaries = rsmpeg::ffi::AVCOL_PRI_BT709;
+                o_codec_ctx.color_trc = rsmpeg::ffi::AVCOL_TRC_BT709;
+                o_codec_ctx.colorspace = rsmpeg::ffi::AVCOL_SPC_BT709;
+                o_codec_ctx.color_range = rsmpeg::ffi::AVCOL_RANGE_MPEG;
+            } else {
+                o_codec_ctx.color_primaries = i_codec_ctx.color_primaries;
+                o_codec_ctx.color_trc = i_codec_ctx.color_trc;
+                o_codec_ctx.colorspace = i_codec_ctx.colorspace;
+                o_codec_ctx.color_range = i_codec_ctx.color_range;
+            }
+        }
+        let mut codec_options = None;
+        match options.rate_control {
+            RateControl::Crf(crf) => codec_options = Some(AVDictionary::new(c"crf", &CString::new(crf.to_string())?, 0)),
+            RateControl::BitRate(bit_rate) => o_codec_ctx.set_bit_rate(bit_rate),
+        }
+        if global_header {
+            o_codec_ctx.set_flags(o_codec_ctx.flags | rsmpeg::ffi::AV_CODEC_FLAG_GLOBAL_HEADER as i32);
+        }
+        let unused_options = o_codec_ctx.open(codec_options).context("output video codec context open failed")?;
+        if unused_options.is_some_and(|options| options.get(c"crf", None, 0).is_some()) {
+            warn!("video encoder {:?} does not support crf, using its default quality", o_codec.name());
+        }
+        o_stream.set_time_base(o_codec_ctx.time_base);
+        o_stream.codecpar_mut().from_context(&o_codec_ctx);
+        if transpose.is_none() {
+            set_display_rotation(&mut o_stream.codecpar_mut(), rotation_cw);
+        }
+        debug!(%o_codec_ctx.width, %o_codec_ctx.height, %o_codec_ctx.bit_rate, "output video codec");
+
+        // 3. filter graph, converting decoded frames to what the encoder accepts
+        let pix_fmt_name = AVPixFmtDescriptorRef::get(pix_fmt)
+            .context("Unknown pixel format")?
+            .name()
+            .to_string_lossy()
+            .into_owned();
+        let mut filters = vec![];
+        if tone_map {
+            AVFilter::get_by_name(c"zscale").context("Tone mapping needs ffmpeg built with libzimg (zscale filter)")?;
+            filters.push(Self::TONE_MAP_FILTERS.to_string());
+        }
+        filters.push(format!("scale={width}:{height}"));
+        filters.extend(transpose.map(str::to_string));
+        filters.push(format!("format=pix_fmts={pix_fmt_name}"));
+        let filter_spec = filters.join(",");
+        let filter_graph = Self::create_filter_graph(&i_codec_ctx, i_stream.time_base, &filter_spec)?;
+
+        Ok(Self {
+            input_stream_index: i_idx,
+            output_stream_index,
+            input_codec_context: i_codec_ctx,
+            output_codec_context: o_codec_ctx,
+            filter_graph,
+            start_pts: i64::MIN,
+        })
+    }
+
+    fn create_filter_graph(i_codec_ctx: &AVCodecContext, time_base: AVRational, filter_spec: &str) -> Result<AVFilterGraph> {
+        let filter_graph = AVFilterGraph::new();
+        let args = format!(
+            "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect={}/{}",
+            i_codec_ctx.width,
+            i_codec_ctx.height,
+            i_codec_ctx.pix_fmt,
+            time_base.num,
+            time_base.den,
+            i_codec_ctx.sample_aspect_ratio.num,
+            i_codec_ctx.sample_aspect_ratio.den.max(1),
+        );
+        let buffer_src = AVFilter::get_by_name(c"buffer").context("No buffer filter")?;
+        let buffer_sink = AVFilter::get_by_name(c"buffersink").context("No buffersink filter")?;
+        {
+            let mut src_ctx = filter_graph.create_filter_context(&buffer_src, c"in", Some(&CString::new(args)?))?;
+            let mut sink_ctx = filter_graph.create_filter_context(&buffer_sink, c"out", None)?;
+            // the graph's input is the output of our source, and vice versa
+            let outputs = AVFilterInOut::new(c"in", &mut src_ctx, 0);
+            let inputs = AVFilterInOut::new(c"out", &mut sink_ctx, 0);
+            filter_graph
+                .parse_ptr(&CString::new(filter_spec)?, Some(inputs), Some(outputs))
+                .with_context(|| format!("parse filter graph failed: {filter_spec}"))?;
+        }
+        filter_graph.config().context("config filter graph failed")?;
+        debug!(%filter_spec, "video filter graph configured");
+        Ok(filter_graph)
+    }
+
+    /// Decode a packet (None to flush), and encode the filtered frames
+    pub fn transcode(&mut self, packet: Option<&AVPacket>, o_fmt_ctx: &mut AVFormatContextOutput) -> Result<()> {
+        self.input_codec_context
+            .send_packet(packet)
+            .context("Send packet to input video codec context failed")?;
+        while let Ok(mut frame) = self.input_codec_context.receive_frame() {
+            if frame.best_effort_timestamp < self.start_pts {
+                continue;
+            }
+            frame.set_pts(frame.best_effort_timestamp);
+            self.filter(Some(frame), o_fmt_ctx)?;
+        }
+        if packet.is_none() {
+            self.filter(None, o_fmt_ctx)?;
+            self.output_codec_context.send_frame(None)?;
+            self.flush_output(o_fmt_ctx)?;
+        }
+        Ok(())
+    }
+
+    fn filter(&mut self, frame: Option<AVFrame>, o_fmt_ctx: &mut AVFormatContextOutput) -> Result<()> {
+        self.filter_graph
+            .get_filter(c"in")
+            .context("No filter named in")?
+            .buffersrc_add_frame(frame, None)
+            .context("add frame to filter graph failed")?;
+        let mut sink = self.filter_graph.get_filter(c"out").context("No filter named out")?;
+        while let Ok(mut frame) = sink.buffersink_get_frame(None) {
+            frame.set_pict_type(rsmpeg::ffi::AV_PICTURE_TYPE_NONE);
+            self.output_codec_context
+                .send_frame(Some(&frame))
+                .context("send frame to output video codec context failed")?;
+            Self::write_output(&mut self.output_codec_context, self.output_stream_index, o_fmt_ctx)?;
+        }
+        Ok(())
+    }
+
+    fn flush_output(&mut self, o_fmt_ctx: &mut AVFormatContextOutput) -> Result<()> {
+        Self::write_output(&mut self.output_codec_context, self.output_stream_index, o_fmt_ctx)
+    }
+
+    fn write_output(o_codec_ctx: &mut AVCodecContext, stream_index: usize, o_fmt_ctx: &mut AVFormatContextOutput) -> Result<()> {
+        while let Ok(mut packet) = o_codec_ctx.receive_packet() {
+            packet.set_stream_index(stream_index as i32);
+            packet.rescale_ts(o_codec_ctx.time_base, o_fmt_ctx.streams()[stream_index].time_base);
+            o_fmt_ctx.interleaved_write_frame(&mut packet).context("write frame failed")?;
+        }
+        Ok(())
+    }
+}
+
+/// A decoded video frame as 8-bit YUV 4:2:0, with the rotation baked in
+pub struct VideoFrame {
+    /// Presentation time in the video
+    pub timestamp_us: i64,
+    pub width: usize,
+    pub height: usize,
+    /// Y plane (width x height), then Cb and Cr planes (each width/2 x height/2, rounded up), without padding
+    pub yuv420p: Vec<u8>,
+}
+
+/// Decode a frame of the video, e.g. to make the still image of a motion photo
+pub struct VideoFrameRequest<'a> {
+    pub input: VideoInput<'a>,
+    pub frame: KeyFrame,
+}
+
+impl VideoFrameRequest<'_> {
+    pub fn execute(&self) -> Result<VideoFrame, ConvertError> {
+        self.decode().map_err(self.input.error())
+    }
+
+    fn decode(&self) -> Result<VideoFrame> {
+        let mut i_fmt_ctx = input_format_context(self.input)?;
+        let (i_idx, i_codec) = i_fmt_ctx
+            .find_best_stream(rsmpeg::ffi::AVMEDIA_TYPE_VIDEO)
+            .context("Find video stream failed")?
+            .context("No video stream found")?;
+        let i_stream = &i_fmt_ctx.streams()[i_idx];
+        let time_base = i_stream.time_base;
+        let mut i_codec_ctx = AVCodecContext::new(&i_codec);
+        i_codec_ctx.apply_codecpar(&i_stream.codecpar())?;
+        i_codec_ctx.set_pkt_timebase(time_base);
+        i_codec_ctx.open(None).context("input video codec context open failed")?;
+        let rotation_cw = display_rotation(&i_stream.codecpar());
+        let hdr = VideoHdr::from_codecpar(&i_stream.codecpar());
+
+        // live photo videos are a few seconds long, so decoding from the start is cheap enough
+        let mut chosen: Option<AVFrame> = None;
+        let mut index = 0;
+        'decode: loop {
+            let packet = i_fmt_ctx.read_packet().context("Read packet failed")?;
+            if packet.as_ref().is_some_and(|p| p.stream_index as usize != i_idx) {
+                continue;
+            }
+            i_codec_ctx
+                .send_packet(packet.as_ref())
+                .context("Send packet to input video codec context failed")?;
+            while let Ok(mut frame) = i_codec_ctx.receive_frame() {
+                let past = match self.frame {
+                    KeyFrame::Timestamp(timestamp_us) => to_us(frame.best_effort_timestamp, time_base) > timestamp_us,
+                    KeyFrame::Index(n) => index > n,
+                };
+                if chosen.is_some() && past {
+                    break 'decode;
+                }
+                frame.set_pts(frame.best_effort_timestamp);
+                chosen = Some(frame);
+                index += 1;
+            }
+            if packet.is_none() {
+                break;
+            }
+        }
+        let frame = chosen.context("No video frame decoded")?;
+        let timestamp_us = to_us(frame.pts, time_base);
+        debug!(%timestamp_us, %rotation_cw, "video frame decoded");
+
+        // an SDR frame in full range BT.601, as JPEG expects
+        let mut filters = vec![];
+        if hdr.is_hdr() {
+            if AVFilter::get_by_name(c"zscale").is_some() {
+                filters.push(VideoConfigure::TONE_MAP_FILTERS);
+            } else {
+                warn!(
+                    ?hdr,
+                    "Tone mapping needs ffmpeg built with libzimg (zscale filter), the frame keeps its HDR signal"
+                );
+            }
+        }
+        filters.extend(transpose_filter(rotation_cw));
+        filters.push("scale=out_range=full:out_color_matrix=bt601");
+        filters.push("format=pix_fmts=yuv420p");
+        let mut filter_graph = VideoConfigure::create_filter_graph(&i_codec_ctx, time_base, &filters.join(","))?;
+        for frame in [Some(frame), None] {
+            filter_graph
+                .get_filter(c"in")
+                .context("No filter named in")?
+                .buffersrc_add_frame(frame, None)
+                .context("add frame to filter graph failed")?;
+        }
+        let frame = filter_graph
+            .get_filter(c"out")
+            .context("No filter named out")?
+            .buffersink_get_frame(None)
+            .context("No frame out of filter graph")?;
+
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        let (w2, h2) = (width.div_ceil(2), height.div_ceil(2));
+        let mut yuv420p = Vec::with_capacity(width * height + 2 * w2 * h2);
+        for (plane, w, h) in [(0, width, height), (1, w2, h2), (2, w2, h2)] {
+            for row in 0..h {
+                let line = unsafe {
+                    let start = frame.data[plane].offset(row as isize * frame.linesize[plane] as isize);
+                    std::slice::from_raw_parts(start, w)
+                };
+                yuv420p.extend_from_slice(line);
+            }
+        }
+        Ok(VideoFrame {
+            timestamp_us,
+            width,
+            height,
+            yuv420p,
+        })
+    }
+}
+
+fn input_format_context(input: VideoInput) -> Result<AVFormatContextInput> {
+    let format_context = match input {
+        VideoInput::File(input) => {
+            let input = input.to_str().context("input path to_str failed")?;
+            let input = CString::new(input)?;
+            let mut input_options = None;
+            rsmpeg::avformat::AVFormatContextInput::open(&input, None, &mut input_options)?
+        }
+        VideoInput::Memory(data) => rsmpeg::avformat::AVFormatContextInput::from_io_context(memory_reader(data.clone()))?,
+    };
+    Ok(format_context)
+}
+
+/// The cursor is set when writing to memory, see [`finish_output`]
+fn output_format_context(output: VideoOutput) -> Result<(AVFormatContextOutput, Option<MemoryCursor>)> {
+    match output {
+        VideoOutput::File(output) => {
+            let output = output.to_str().context("output path to_str failed")?;
+            let output = CString::new(output)?;
+            let format_context = rsmpeg::avformat::AVFormatContextOutput::create(&output, None)?;
+            Ok((format_context, None))
+        }
+        VideoOutput::Memory => {
+            let cursor = Arc::new(Mutex::new(Cursor::new(vec![])));
+            // the file name only selects the muxer
+            let io_context = memory_writer(cursor.clone());
+            let format_context = rsmpeg::avformat::AVFormatContextOutput::create(c"memory.mp4", Some(io_context))?;
+            Ok((format_context, Some(cursor)))
+        }
+    }
+}
+
+/// Close the output, and take the written data if it was written to memory.
+/// The muxer can't apply faststart in memory, so `moov` is moved to the front here.
+fn finish_output(o_fmt_ctx: AVFormatContextOutput, memory: Option<MemoryCursor>) -> Result<Option<Vec<u8>>> {
+    // flushes and drops the io context
+    drop(o_fmt_ctx);
+    let Some(memory) = memory else {
+        return Ok(None);
+    };
+    let data = std::mem::take(memory.lock().unwrap().get_mut());
+    let data = super::mp4::faststart(data).context("move moov to the front failed")?;
+    Ok(Some(data))
+}
+
+/// Shared between the read / write and seek callbacks of an io context
+type MemoryCursor<T = Vec<u8>> = Arc<Mutex<Cursor<T>>>;
+
+const MEMORY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Reads the shared input, without copying it
+fn memory_reader(data: Arc<[u8]>) -> AVIOContextContainer {
+    let cursor = Arc::new(Mutex::new(Cursor::new(data)));
+    let seek = memory_seek(cursor.clone());
+    let read_packet = Box::new(move |_: &mut Vec<u8>, buf: &mut [u8]| -> i32 {
+        match cursor.lock().unwrap().read(buf) {
+            Ok(0) => rsmpeg::ffi::AVERROR_EOF,
+            Ok(read) => read as i32,
+            Err(_) => rsmpeg::ffi::AVERROR(rsmpeg::ffi::EIO),
+        }
+    });
+    let io_context = AVIOContextCustom::alloc_context(AVMem::new(MEMORY_BUFFER_SIZE), false, vec![], Some(read_packet), None, Some(seek));
+    AVIOContextContainer::Custom(io_context)
+}
+
+fn memory_writer(cursor: MemoryCursor) -> AVIOContextContainer {
+    let seek = memory_seek(cursor.clone());
+    let write_packet = Box::new(move |_: &mut Vec<u8>, buf: &[u8]| -> i32 {
+        match cursor.lock().unwrap().write_all(buf) {
+            Ok(()) => buf.len() as i32,
+            Err(_) => rsmpeg::ffi::AVERROR(rsmpeg::ffi::ENOMEM),
+        }
+    });
+    let io_context = AVIOContextCustom::alloc_context(AVMem::new(MEMORY_BUFFER_SIZE), true, vec![], None, Some(write_packet), Some(seek));
+    AVIOContextContainer::Custom(io_context)
+}
+
+fn memory_seek<T: AsRef<[u8]> + Send + 'static>(cursor: MemoryCursor<T>) -> rsmpeg::avformat::SeekCallback {
+    Box::new(move |_: &mut Vec<u8>, offset: i64, whence: i32| -> i64 {
+        let mut cursor = cursor.lock().unwrap();
+        if whence & rsmpeg::ffi::AVSEEK_SIZE as i32 != 0 {
+            return cursor.get_ref().as_ref().len() as i64;
+        }
+        let position = match whence & !(rsmpeg::ffi::AVSEEK_FORCE as i32) {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return rsmpeg::ffi::AVERROR(rsmpeg::ffi::EINVAL) as i64,
+        };
+        match cursor.seek(position) {
+            Ok(position) => position as i64,
+            Err(_) => rsmpeg::ffi::AVERROR(rsmpeg::ffi::EINVAL) as i64,
+        }
+    })
+}
+
+/// Metadata keys that are derived by the muxer, and must not be copied from the input
+const MUXER_METADATA_KEYS: [&str; 6] = [
+    "major_brand",
+    "minor_version",
+    "compatible_brands",
+    "encoder",
+    "handler_name",
+    "vendor_id",
+];
+
+/// Options for the MP4 muxer. `use_metadata_tags` keeps arbitrary keys, such as `com.apple.quicktime.*`,
+/// and `faststart` moves the `moov` atom to the front for quick playback start.
+/// faststart reopens the output by its file name, so it only works with file outputs, see [`finish_output`].
+fn muxer_options(faststart: bool) -> AVDictionary {
+    match faststart {
+        true => AVDictionary::new(c"movflags", c"use_metadata_tags+faststart", 0),
+        false => AVDictionary::new(c"movflags", c"use_metadata_tags", 0),
+    }
+}
+
+/// Copy the container metadata, and the metadata of each (input, output) stream pair, that the policy keeps.
+/// The creation dates (`creation_time` in UTC, `com.apple.quicktime.creationdate` in local time) are shifted.
+fn copy_metadata(
+    i_fmt_ctx: &AVFormatContextInput,
+    o_fmt_ctx: &mut AVFormatContextOutput,
+    streams: &[(usize, usize)],
+    policy: crate::utils::MetadataPolicy,
+    time_shift: Option<crate::utils::TimeShift>,
+) {
+    let filter = |metadata: Option<AVDictionaryRef>| -> Option<AVDictionary> {
+        let metadata = metadata?;
+        let mut filtered: Option<AVDictionary> = None;
+        for entry in metadata.iter() {
+            let key = entry.key();
+            if MUXER_METADATA_KEYS.iter().any(|skipped| key.to_bytes() == skipped.as_bytes()) {
+                continue;
+            }
+            if !policy.keeps_video_key(&key.to_string_lossy()) {
+                trace!(key = ?key, "metadata removed by policy");
+                continue;
+            }
+            let mut value = entry.value().to_owned();
+            let lowercase_key = key.to_string_lossy().to_ascii_lowercase();
+            if lowercase_key == "creation_time" || lowercase_key.ends_with("creationdate") {
+                let shifted = time_shift.and_then(|shift| shift.shift_date(&value.to_string_lossy()));
+                if let Some(shifted) = shifted.and_then(|shifted| CString::new(shifted).ok()) {
+                    debug!(key = ?key, from = ?value, to = ?shifted, "shift video date");
+                    value = shifted;
+                }
+            }
+            trace!(key = ?key, value = ?value, "copy metadata");
+            filtered = Some(match filtered {
+                Some(dict) => dict.set(key, &value, 0),
+                None => AVDictionary::new(key, &value, 0),
+            });
+        }
+        filtered
+    };
+
+    // AVFormatContextOutput has no setters for its own metadata, nor mutable access to its streams
+    let o_fmt_ctx = unsafe { o_fmt_ctx.deref_mut() };
+    if let Some(metadata) = filter(i_fmt_ctx.metadata()) {
+        unsafe { rsmpeg::ffi::av_dict_free(&mut o_fmt_ctx.metadata) };
+        o_fmt_ctx.metadata = metadata.into_raw().as_ptr();
+    }
+    for &(input_idx, output_idx) in streams {
+        assert!(output_idx < o_fmt_ctx.nb_streams as usize);
+        if let Some(metadata) = filter(i_fmt_ctx.streams()[input_idx].metadata()) {
+            unsafe {
+                let o_stream = *o_fmt_ctx.streams.add(output_idx);
+                rsmpeg::ffi::av_dict_free(&mut (*o_stream).metadata);
+                (*o_stream).metadata = metadata.into_raw().as_ptr();
+            }
+        }
+    }
+}
+
+/// Add a stream copy of the best video stream. Returns (input index, output index, Dolby Vision stripper).
+/// The stripper is set if Dolby Vision must be removed from the copied packets.
+fn copy_video_stream(
+    i_fmt_ctx: &AVFormatContextInput,
+    o_fmt_ctx: &mut AVFormatContextOutput,
+    hdr: HdrPolicy,
+) -> Result<(usize, usize, Option<DolbyVisionStrip>)> {
+    let (input_video_idx, _) = i_fmt_ctx
+        .find_best_stream(rsmpeg::ffi::AVMEDIA_TYPE_VIDEO)
+        .context("Find video stream failed")?
+        .context("No video stream found")?;
+    let input_video = &i_fmt_ctx.streams()[input_video_idx];
+    let mut output_video = o_fmt_ctx.new_stream();
+    output_video.codecpar_mut().copy(&input_video.codecpar());
+    // Android only honours rotations by multiples of 90 degrees
+    set_display_rotation(&mut output_video.codecpar_mut(), display_rotation(&input_video.codecpar()));
+    let video_hdr = VideoHdr::from_codecpar(&input_video.codecpar());
+    let strip_dolby_vision = match (hdr, video_hdr.dolby_vision_profile) {
+        (HdrPolicy::StripDolbyVision, Some(profile)) => {
+            // profile 5 has no backward compatible base layer
+            anyhow::ensure!(
+                video_hdr.dolby_vision_compatibility_id.is_some_and(|id| id != 0),
+                "Dolby Vision profile {profile} has no backward compatible base layer"
+            );
+            debug!(%profile, ?video_hdr.dolby_vision_compatibility_id, "strip Dolby Vision");
+            let strip = DolbyVisionStrip::new(&input_video.codecpar());
+            strip.strip_codecpar(&mut output_video.codecpar_mut());
+            Some(strip)
+        }
+        _ => None,
+    };
+    Ok((input_video_idx, output_video.index as usize, strip_dolby_vision))
+}
+
+/// Add a stream copy of the best audio stream. Returns (input index, output index).
+fn copy_audio_stream(i_fmt_ctx: &AVFormatContextInput, o_fmt_ctx: &mut AVFormatContextOutput) -> Result<(usize, usize)> {
+    let (input_audio_idx, _) = i_fmt_ctx
+        .find_best_stream(rsmpeg::ffi::AVMEDIA_TYPE_AUDIO)
+        .context("Find audio stream failed")?
+        .context("No audio stream found")?;
+    let mut output_audio = o_fmt_ctx.new_stream();
+    output_audio.codecpar_mut().copy(&i_fmt_ctx.streams()[input_audio_idx].codecpar());
+    Ok((input_audio_idx, output_audio.index as usize))
+}
+
+/// Removes Dolby Vision from copied HEVC packets: the RPU and enhancement layer NAL units are dropped,
+/// leaving the backward compatible base layer
+struct DolbyVisionStrip {
+    /// Size of the NAL unit length prefix in packets
+    nal_length_size: usize,
+}
+
+impl DolbyVisionStrip {
+    const NAL_UNIT_RPU: u8 = 62;
+    const NAL_UNIT_ENHANCEMENT_LAYER: u8 = 63;
+
+    fn new(codecpar: &AVCodecParameters) -> Self {
+        // hvcC: lengthSizeMinusOne is the low 2 bits of byte 21
+        let nal_length_size = match codecpar.extradata_size {
+            size if size > 21 => (unsafe { *codecpar.extradata.add(21) } & 0b11) as usize + 1,
+            _ => 4,
+        };
+        Self { nal_length_size }
+    }
+
+    /// Drop the Dolby Vision configuration record, and use the plain HEVC sample entry
+    fn strip_codecpar(&self, codecpar: &mut AVCodecParameters) {
+        let codecpar = codecpar.as_mut_ptr();
+        unsafe {
+            rsmpeg::ffi::av_packet_side_data_remove(
+                (*codecpar).coded_side_data,
+                &mut (*codecpar).nb_coded_side_data,
+                rsmpeg::ffi::AV_PKT_DATA_DOVI_CONF,
+            );
+            // dvh1 -> hvc1
+            (*codecpar).codec_tag = u32::from_le_bytes(*b"hvc1");
+        }
+    }
+
+    fn apply(&self, packet: &mut AVPacket) -> Result<()> {
+        let data = unsafe { std::slice::from_raw_parts(packet.data, packet.size as usize) };
+        let mut stripped = Vec::with_capacity(data.len());
+        let mut rest = data;
+        while rest.len() >= self.nal_length_size {
+            let (length, body) = rest.split_at(self.nal_length_size);
+            let length = length.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+            anyhow::ensure!(length <= body.len(), "Invalid NAL unit length");
+            let nal_unit_type = body.first().map(|header| (header >> 1) & 0x3f);
+            if !matches!(nal_unit_type, Some(Self::NAL_UNIT_RPU | Self::NAL_UNIT_ENHANCEMENT_LAYER)) {
+                stripped.extend_from_slice(&rest[..self.nal_length_size + length]);
+            }
+            rest = &body[length..];
+        }
+        if stripped.len() == data.len() {
+            return Ok(());
+        }
+        // the packet is shrunk in place
+        unsafe {
+            if rsmpeg::ffi::av_packet_make_writable(packet.as_mut_ptr()) < 0 {
+                anyhow::bail!("make packet writable failed");
+            }
+            std::ptr::copy_nonoverlapping(stripped.as_ptr(), packet.deref_mut().data, stripped.len());
+            rsmpeg::ffi::av_shrink_packet(packet.as_mut_ptr(), stripped.len() as i32);
+        }
+        Ok(())
+    }
+}
+
+/// HDR format of the best video stream
+fn best_video_hdr(i_fmt_ctx: &AVFormatContextInput) -> Result<VideoHdr> {
+    let (index, _) = i_fmt_ctx
+        .find_best_stream(rsmpeg::ffi::AVMEDIA_TYPE_VIDEO)
+        .context("Find video stream failed")?
+        .context("No video stream found")?;
+    let hdr = VideoHdr::from_codecpar(&i_fmt_ctx.streams()[index].codecpar());
+    Ok(hdr)
+}
+
+/// Clockwise rotation of the best video stream, see [`display_rotation`]
+fn best_video_rotation(i_fmt_ctx: &AVFormatContextInput) -> Result<i32> {
+    let (index, _) = i_fmt_ctx
+        .find_best_stream(rsmpeg::ffi::AVMEDIA_TYPE_VIDEO)
+        .context("Find video stream failed")?
+        .context("No video stream found")?;
+    let rotation = display_rotation(&i_fmt_ctx.streams()[index].codecpar());
+    Ok(rotation)
+}
+
+/// Filter that turns the pixels by a clockwise display rotation
+fn transpose_filter(rotation_cw: i32) -> Option<&'static str> {
+    match rotation_cw {
+        90 => Some("transpose=clock"),
+        180 => Some("hflip,vflip"),
+        270 => Some("transpose=cclock"),
+        _ => None,
+    }
+}
+
+/// Clockwise rotation of the display matrix in degrees, snapped to 0, 90, 180 or 270
+fn display_rotation(codecpar: &AVCodecParameters) -> i32 {
+    let side_data = unsafe {
+        rsmpeg::ffi::av_packet_side_data_get(
+            codecpar.coded_side_data,
+            codecpar.nb_coded_side_data,
+            rsmpeg::ffi::AV_PKT_DATA_DISPLAYMATRIX,
+        )
+    };
+    if side_data.is_null() || unsafe { (*side_data).size } < 9 * std::mem::size_of::<i32>() {
+        return 0;
+    }
+    // counterclockwise, in [-180, 180]
+    let rotation = unsafe { rsmpeg::ffi::av_display_rotation_get((*side_data).data as *const i32) };
+    if rotation.is_nan() {
+        return 0;
+    }
+    ((-rotation / 90.0).round() as i32 * 90).rem_euclid(360)
+}
+
+/// Replace the display matrix with a pure clockwise rotation. 0 removes the display matrix.
+fn set_display_rotation(codecpar: &mut AVCodecParameters, rotation_cw: i32) {
+    let codecpar = codecpar.as_mut_ptr();
+    unsafe {
+        rsmpeg::ffi::av_packet_side_data_remove(
+            (*codecpar).coded_side_data,
+            &mut (*codecpar).nb_coded_side_data,
+            rsmpeg::ffi::AV_PKT_DATA_DISPLAYMATRIX,
+        );
+        if rotation_cw == 0 {
+            return;
+        }
+        let side_data = rsmpeg::ffi::av_packet_side_data_new(
+            &mut (*codecpar).coded_side_data,
+            &mut (*codecpar).nb_coded_side_data,
+            rsmpeg::ffi::AV_PKT_DATA_DISPLAYMATRIX,
+            9 * std::mem::size_of::<i32>(),
+            0,
+        );
+        if !side_data.is_null() {
+            rsmpeg::ffi::av_display_rotation_set((*side_data).data as *mut i32, -rotation_cw as f64);
+        }
+    }
+}
+
+fn to_us(ts: i64, time_base: AVRational) -> i64 {
+    rsmpeg::avutil::av_rescale_q(ts, time_base, rsmpeg::ffi::AV_TIME_BASE_Q)
+}
+
+/// A cut of the input, applied packet by packet
+struct Cut {
+    /// Where the output starts in the input (us). The pts of a video keyframe, unless the video is re-encoded.
+    start_us: i64,
+    end_us: i64,
+    /// dts (us) of the keyframe to decode from. Video packets decoded before it are dropped.
+    video_start_dts_us: i64,
+    /// The start that was asked for (us)
+    requested_start_us: i64,
+}
+
+impl Cut {
+    fn full() -> Self {
+        Self {
+            start_us: 0,
+            end_us: i64::MAX,
+            video_start_dts_us: i64::MIN,
+            requested_start_us: 0,
+        }
+    }
+
+    /// Whether the keyframe is at the requested start, so that a copied video starts there
+    fn is_exact(&self) -> bool {
+        self.start_us == self.requested_start_us
+    }
+
+    /// Start at the requested time instead of the keyframe, for a re-encoded video.
+    /// The frames decoded from the keyframe are shifted before 0, and must be dropped.
+    fn start_exactly(&mut self) {
+        self.start_us = self.requested_start_us;
+    }
+
+    /// Scan the video packets for the last keyframe not after `range.start_us`
+    fn find(input: VideoInput, video_idx: usize, range: TimeRange) -> Result<Self> {
+        let mut i_fmt_ctx = input_format_context(input)?;
+        let time_base = i_fmt_ctx.streams()[video_idx].time_base;
+        let mut keyframe = None;
+        while let Some(packet) = i_fmt_ctx.read_packet().context("read packet failed")? {
+            if packet.stream_index != video_idx as i32 || packet.flags & rsmpeg::ffi::AV_PKT_FLAG_KEY as i32 == 0 {
+                continue;
+            }
+            let pts_us = to_us(Self::pts(&packet), time_base);
+            if keyframe.is_some() && pts_us > range.start_us {
+                break;
+            }
+            keyframe = Some((pts_us, to_us(Self::dts(&packet), time_base)));
+        }
+        let (start_us, video_start_dts_us) = keyframe.context("No video keyframe found")?;
+        debug!(requested = range.start_us, start_us, "trim start aligned to keyframe");
+        Ok(Self {
+            start_us,
+            end_us: range.end_us,
+            video_start_dts_us,
+            requested_start_us: range.start_us,
+        })
+    }
+
+    fn pts(packet: &AVPacket) -> i64 {
+        if packet.pts == rsmpeg::ffi::AV_NOPTS_VALUE {
+            packet.dts
+        } else {
+            packet.pts
+        }
+    }
+
+    fn dts(packet: &AVPacket) -> i64 {
+        if packet.dts == rsmpeg::ffi::AV_NOPTS_VALUE {
+            packet.pts
+        } else {
+            packet.dts
+        }
+    }
+
+    /// Video is cut in decode order, so that every kept frame has its references
+    fn keep_video(&self, packet: &AVPacket, time_base: AVRational) -> bool {
+        let dts_us = to_us(Self::dts(packet), time_base);
+        self.video_start_dts_us <= dts_us && dts_us < self.end_us
+    }
+
+    fn keep_audio(&self, packet: &AVPacket, time_base: AVRational) -> bool {
+        let pts_us = to_us(Self::pts(packet), time_base);
+        self.start_us <= pts_us && pts_us < self.end_us
+    }
+
+    /// Move timestamps so that the output starts at 0
+    fn shift(&self, packet: &mut AVPacket, time_base: AVRational) {
+        let offset = rsmpeg::avutil::av_rescale_q(self.start_us, rsmpeg::ffi::AV_TIME_BASE_Q, time_base);
+        if packet.pts != rsmpeg::ffi::AV_NOPTS_VALUE {
+            packet.set_pts(packet.pts - offset);
+        }
+        if packet.dts != rsmpeg::ffi::AV_NOPTS_VALUE {
+            packet.set_dts(packet.dts - offset);
+        }
+    }
+
+    /// The kept range, given the input duration (us)
+    fn range(&self, duration_us: i64) -> TimeRange {
+        TimeRange {
+            start_us: self.start_us,
+            end_us: self.end_us.min(duration_us),
+        }
+    }
+}
+
 pub struct VideoUtils {}
 impl VideoUtils {
-    pub fn get_audio_codec(path: &Path) -> anyhow::Result<Option<String>> {
-        let path = CString::new(path.to_string_lossy().to_string())?;
-        let mut options = None;
-        let format_context = rsmpeg::avformat::AVFormatContextInput::open(&path, None, &mut options)?;
+    pub fn get_audio_codec<'a>(input: impl Into<VideoInput<'a>>) -> anyhow::Result<Option<String>> {
+        let format_context = input_format_context(input.into())?;
 
         let stream = format_context
             .find_best_stream(rsmpeg::ffi::AVMEDIA_TYPE_AUDIO)
@@ -272,4 +1615,87 @@ impl VideoUtils {
         let codec = codec.name().to_string_lossy().into_owned();
         Ok(Some(codec))
     }
+
+    /// Clockwise rotation of the video in degrees: 0, 90, 180 or 270
+    pub fn get_rotation<'a>(input: impl Into<VideoInput<'a>>) -> anyhow::Result<i32> {
+        let format_context = input_format_context(input.into())?;
+        best_video_rotation(&format_context)
+    }
+
+    /// HDR format of the video: Dolby Vision, HLG or PQ
+    pub fn get_hdr<'a>(input: impl Into<VideoInput<'a>>) -> anyhow::Result<VideoHdr> {
+        let format_context = input_format_context(input.into())?;
+        best_video_hdr(&format_context)
+    }
+
+    /// Duration of the video, in microseconds
+    pub fn get_duration_us<'a>(input: impl Into<VideoInput<'a>>) -> anyhow::Result<i64> {
+        let format_context = input_format_context(input.into())?;
+        anyhow::ensure!(format_context.duration != rsmpeg::ffi::AV_NOPTS_VALUE, "Unknown video duration");
+        Ok(format_context.duration)
+    }
+
+    /// Capture time of the video: the QuickTime creation date (in local time, with its offset), or else `creation_time` (UTC)
+    pub fn get_creation_time<'a>(input: impl Into<VideoInput<'a>>) -> anyhow::Result<Option<std::time::SystemTime>> {
+        let format_context = input_format_context(input.into())?;
+        let Some(metadata) = format_context.metadata() else {
+            return Ok(None);
+        };
+        let time = [c"com.apple.quicktime.creationdate", c"creation_time"].into_iter().find_map(|key| {
+            let entry = metadata.get(key, None, 0)?;
+            crate::utils::parse_time(&entry.value().to_string_lossy(), None)
+        });
+        Ok(time)
+    }
+
+    /// Time zone offset of the QuickTime creation date, in seconds east of UTC. None if the video has no local creation date.
+    pub fn get_time_zone_offset<'a>(input: impl Into<VideoInput<'a>>) -> anyhow::Result<Option<i64>> {
+        let format_context = input_format_context(input.into())?;
+        let Some(metadata) = format_context.metadata() else {
+            return Ok(None);
+        };
+        let offset = metadata
+            .get(c"com.apple.quicktime.creationdate", None, 0)
+            .and_then(|entry| crate::utils::date_offset(&entry.value().to_string_lossy()));
+        Ok(offset)
+    }
+
+    /// Time of the key photo of a live photo video, in microseconds.
+    /// It's the sample of the timed metadata track with the `com.apple.quicktime.still-image-time` key.
+    /// None if the video has no such track.
+    pub fn get_still_image_time_us<'a>(input: impl Into<VideoInput<'a>>) -> anyhow::Result<Option<i64>> {
+        const KEY: &[u8] = b"com.apple.quicktime.still-image-time";
+        let input = input.into();
+        let moov = match input {
+            VideoInput::File(path) => super::mp4::read_moov(std::fs::File::open(path)?)?,
+            VideoInput::Memory(data) => super::mp4::read_moov(Cursor::new(&data[..]))?,
+        };
+        let mut track_id = None;
+        for trak in super::mp4::tracks(&moov)? {
+            if moov[trak.body.clone()].windows(KEY.len()).any(|window| window == KEY) {
+                track_id = Some(super::mp4::track_id(&moov, &trak)?);
+                break;
+            }
+        }
+        let Some(track_id) = track_id else {
+            return Ok(None);
+        };
+
+        // ffmpeg uses the track id as the stream id
+        let mut format_context = input_format_context(input)?;
+        let stream = format_context
+            .streams()
+            .iter()
+            .find(|stream| stream.id as u32 == track_id)
+            .map(|stream| (stream.index as usize, stream.time_base));
+        let Some((stream_idx, time_base)) = stream else {
+            return Ok(None);
+        };
+        while let Some(packet) = format_context.read_packet()? {
+            if packet.stream_index as usize == stream_idx {
+                return Ok(Some(to_us(Cut::pts(&packet), time_base)));
+            }
+        }
+        Ok(None)
+    }
 }
@@ -1,51 +1,601 @@
-use anyhow::Context;
-use std::path::{Path, PathBuf};
+use anyhow::{bail, Context};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Stdio},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc, Condvar, Mutex, OnceLock, Weak,
+    },
+    time::{Duration, Instant},
+};
 
-#[derive(Default)]
+/// Commands are run by long-lived `exiftool -stay_open` processes, shared by all `ExifTool`s with the same path and config.
+/// The processes exit when the last `ExifTool` that used them is dropped, so share one `ExifTool` between conversions.
+#[derive(Debug, Default)]
 pub struct ExifTool {
     pub path: Option<PathBuf>,
+    /// Content of a `-config` file, e.g. for user-defined tags
+    pub config: Option<&'static str>,
+    /// The pools this has used, kept alive with it
+    pools: Mutex<Vec<Arc<Pool>>>,
+}
+
+/// The exiftool executable was not found
+#[derive(Debug, thiserror::Error)]
+#[error("exiftool not found: {}", program.display())]
+pub struct ExifToolNotFound {
+    pub program: PathBuf,
+}
+
+/// Output of one exiftool command
+#[derive(Debug, Default)]
+pub struct ExifToolOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl ExifToolOutput {
+    /// exiftool reports failures on stderr as "Error: ..." lines, while warnings do not fail the command
+    pub fn error(&self) -> Option<&str> {
+        self.stderr.lines().find(|line| line.starts_with("Error"))
+    }
 }
 
 impl ExifTool {
     pub fn new() -> Self {
-        Self { path: None }
+        Self::default()
     }
     pub fn with_path(path: impl Into<PathBuf>) -> Self {
-        Self { path: Some(path.into()) }
+        Self {
+            path: Some(path.into()),
+            ..Self::default()
+        }
+    }
+    pub fn with_config(self, config: &'static str) -> Self {
+        Self {
+            config: Some(config),
+            ..self
+        }
     }
+    /// A new exiftool process, for one-off commands
     pub fn command(&self) -> std::process::Command {
-        std::process::Command::new(self.path.as_ref().and_then(|e| e.as_os_str().to_str()).unwrap_or("exiftool"))
+        std::process::Command::new(self.program())
+    }
+    fn program(&self) -> &OsStr {
+        self.path.as_ref().map(|e| e.as_os_str()).unwrap_or(OsStr::new("exiftool"))
+    }
+    /// Run a command with the arguments on a shared exiftool process
+    pub fn execute(&self, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> anyhow::Result<ExifToolOutput> {
+        self.execute_with_config(self.config, args)
+    }
+    fn execute_with_config(
+        &self,
+        config: Option<&'static str>,
+        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    ) -> anyhow::Result<ExifToolOutput> {
+        let args = args.into_iter().map(|arg| arg.as_ref().to_os_string()).collect::<Vec<_>>();
+        self.pool(config)?.execute(&args)
+    }
+    /// The processes for the path and `config`, shared with the other `ExifTool`s using them
+    fn pool(&self, config: Option<&'static str>) -> anyhow::Result<Arc<Pool>> {
+        let key = (PathBuf::from(self.program()), config);
+        let mut pools = self.pools.lock().unwrap();
+        if let Some(pool) = pools.iter().find(|pool| pool.key == key) {
+            return Ok(pool.clone());
+        }
+        let pool = Pool::get(key)?;
+        pools.push(pool.clone());
+        Ok(pool)
     }
     pub fn get_value(&self, file: impl AsRef<Path>, key: &str) -> anyhow::Result<Option<String>> {
         let output = self
-            .command()
-            .arg(format!("-{key}"))
-            .args(["-s", "-s", "-s"])
-            .arg("-m") // ignore minor error
-            .arg(file.as_ref().as_os_str())
-            .output()
+            .execute([
+                OsStr::new(&format!("-{key}")),
+                OsStr::new("-s"),
+                OsStr::new("-s"),
+                OsStr::new("-s"),
+                OsStr::new("-m"), // ignore minor error
+                file.as_ref().as_os_str(),
+            ])
             .context("Run exiftool command failed. Is exiftool path corrent?")?;
-        if !output.status.success() {
-            return Err(anyhow::anyhow!("exiftool failed: {}", String::from_utf8_lossy(&output.stderr)));
+        if let Some(error) = output.error() {
+            return Err(anyhow::anyhow!("exiftool failed: {error}"));
         }
-        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        let value = output.stdout.trim().to_string();
         if value.is_empty() {
             return Ok(None);
         }
         Ok(Some(value))
     }
+    /// Read the tags in one command, into a struct with a field for each tag name (without group)
+    pub fn get_values<T: serde::de::DeserializeOwned>(&self, file: impl AsRef<Path>, tags: &[&str]) -> anyhow::Result<T> {
+        let mut args = vec![OsString::from("-json"), OsString::from("-n"), OsString::from("-m")];
+        args.extend(tags.iter().map(|tag| OsString::from(format!("-{tag}"))));
+        args.push(file.as_ref().as_os_str().to_os_string());
+        let output = self
+            .execute(args)
+            .context("Run exiftool command failed. Is exiftool path corrent?")?;
+        if let Some(error) = output.error() {
+            return Err(anyhow::anyhow!("exiftool failed: {error}"));
+        }
+        // one object per file
+        let [values]: [T; 1] = serde_json::from_str::<Vec<T>>(&output.stdout)
+            .context("parse exiftool json output failed")?
+            .try_into()
+            .map_err(|values: Vec<T>| anyhow::anyhow!("exiftool returned {} objects for one file", values.len()))?;
+        Ok(values)
+    }
+    pub fn photo_metadata(&self, file: impl AsRef<Path>) -> anyhow::Result<super::PhotoMetadata> {
+        self.get_values(file, super::PhotoMetadata::TAGS)
+    }
     pub fn copy_meta(&self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> anyhow::Result<()> {
+        let output = self.execute([
+            OsStr::new("-TagsFromFile"),
+            src.as_ref().as_os_str(),
+            OsStr::new("-Orientation="),
+            OsStr::new("-overwrite_original"),
+            dst.as_ref().as_os_str(),
+        ])?;
+        if let Some(error) = output.error() {
+            return Err(anyhow::anyhow!("exiftool failed: {error}"));
+        }
+        Ok(())
+    }
+    /// Copy capture time, location and camera of a QuickTime video to an image
+    pub fn copy_video_meta(&self, src: impl AsRef<Path>, dst: impl AsRef<Path>) -> anyhow::Result<()> {
+        let output = self.execute([
+            OsStr::new("-TagsFromFile"),
+            src.as_ref().as_os_str(),
+            // CreationDate is in the local time zone, like EXIF dates. CreateDate is UTC, so it is not copied.
+            OsStr::new("-AllDates<CreationDate"),
+            OsStr::new("-GPSLatitude*<GPSLatitude"),
+            OsStr::new("-GPSLongitude*<GPSLongitude"),
+            OsStr::new("-Make"),
+            OsStr::new("-Model"),
+            OsStr::new("-m"), // ignore minor error
+            OsStr::new("-overwrite_original"),
+            dst.as_ref().as_os_str(),
+        ])?;
+        if let Some(error) = output.error() {
+            return Err(anyhow::anyhow!("exiftool failed: {error}"));
+        }
+        Ok(())
+    }
+}
+
+impl super::MetadataBackend for ExifTool {
+    fn read(&self, file: &Path) -> anyhow::Result<super::PhotoMetadata> {
+        self.photo_metadata(file)
+    }
+    fn copy_tags(&self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        self.copy_meta(src, dst)
+    }
+    fn copy_video_tags(&self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        self.copy_video_meta(src, dst)
+    }
+    fn set_image_size(&self, file: &Path, width: usize, height: usize) -> anyhow::Result<()> {
+        let output = self.execute([
+            OsStr::new(&format!("-ExifImageWidth={width}")),
+            OsStr::new(&format!("-ExifImageHeight={height}")),
+            OsStr::new("-m"),
+            OsStr::new("-overwrite_original"),
+            file.as_os_str(),
+        ])?;
+        if let Some(error) = output.error() {
+            return Err(anyhow::anyhow!("exiftool failed: {error}"));
+        }
+        Ok(())
+    }
+    fn shift_dates(&self, file: &Path, seconds: i64, offset: Option<&str>) -> anyhow::Result<()> {
+        let sign = if seconds < 0 { '-' } else { '+' };
+        let seconds = seconds.unsigned_abs();
+        // "h:m:s" shifts the time, and the date with it
+        let shift = format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60);
+        let mut args = vec![
+            OsString::from(format!("-AllDates{sign}={shift}")),
+            OsString::from(format!("-XMP-photoshop:DateCreated{sign}={shift}")),
+        ];
+        if let Some(offset) = offset {
+            args.extend(
+                ["-OffsetTime", "-OffsetTimeOriginal", "-OffsetTimeDigitized"].map(|tag| OsString::from(format!("{tag}={offset}"))),
+            );
+        }
+        args.extend([OsString::from("-m"), OsString::from("-overwrite_original")]);
+        args.push(file.as_os_str().to_os_string());
+        let output = self.execute(args)?;
+        if let Some(error) = output.error() {
+            return Err(anyhow::anyhow!("exiftool failed: {error}"));
+        }
+        Ok(())
+    }
+    fn apply_policy(&self, file: &Path, policy: super::MetadataPolicy) -> anyhow::Result<()> {
+        let tags: &[&str] = match policy {
+            super::MetadataPolicy::KeepAll => return Ok(()),
+            super::MetadataPolicy::StripLocation => &["-GPS*=", "-*Location*=", "-*City=", "-*State=", "-*Country*="],
+            super::MetadataPolicy::StripDevice => &["-MakerNotes:all=", "-*SerialNumber="],
+            // delete everything, then copy back the dates, and the XMP describing the Ultra HDR gain map
+            super::MetadataPolicy::DateOnly => &[
+                "-EXIF:all=",
+                "-IPTC:all=",
+                "-XMP:all=",
+                "-Photoshop:all=",
+                "-TagsFromFile",
+                "@",
+                "-EXIF:DateTimeOriginal",
+                "-EXIF:CreateDate",
+                "-EXIF:ModifyDate",
+                "-EXIF:OffsetTime*",
+                "-EXIF:SubSecTime*",
+                "-XMP-hdrgm:all",
+                "-XMP-GContainer:all",
+            ],
+        };
+        let mut args = tags.iter().map(OsStr::new).collect::<Vec<_>>();
+        args.extend([OsStr::new("-m"), OsStr::new("-overwrite_original"), file.as_os_str()]);
+        let output = self.execute(args)?;
+        if let Some(error) = output.error() {
+            return Err(anyhow::anyhow!("exiftool failed: {error}"));
+        }
+        Ok(())
+    }
+    fn write_motion_photo(&self, file: &Path, video_size: u64, presentation_timestamp_us: i64) -> anyhow::Result<()> {
+        // defines the XiaomiTag and the GCamera XMP namespace
+        const EXIFTOOL_CONFIG: &str = include_str!("exiftool.config");
         let output = self
-            .command()
-            .arg("-TagsFromFile")
-            .arg(src.as_ref().as_os_str())
-            .arg("-Orientation=")
-            .arg("-overwrite_original")
-            .arg(dst.as_ref().as_os_str())
-            .output()?;
-        if !output.status.success() {
-            return Err(anyhow::anyhow!("exiftool failed: {}", String::from_utf8_lossy(&output.stderr)));
+            .execute_with_config(
+                Some(EXIFTOOL_CONFIG),
+                [
+                    OsStr::new("-XMP-GCamera:MicroVideo=1"),
+                    OsStr::new("-XMP-GCamera:MicroVideoVersion=1"),
+                    OsStr::new(&format!(
+                        "-XMP-GCamera:MicroVideoPresentationTimestampUs={presentation_timestamp_us}"
+                    )),
+                    OsStr::new(&format!("-XMP-GCamera:MicroVideoOffset={video_size}")),
+                    OsStr::new("-XiaomiTag=1"),
+                    OsStr::new("-overwrite_original"),
+                    OsStr::new("-m"), // ignore minor error, as per https://exiftool.org/forum/index.php?topic=7341.0
+                    file.as_os_str(),
+                ],
+            )
+            .context("Run exiftool failed when writing tags")?;
+        if let Some(error) = output.error() {
+            bail!("Run exiftool failed when writing exif tags: {}", error);
         }
         Ok(())
     }
 }
+
+/// A command that takes longer than this is killed with its process
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Path and config of the processes of a pool
+type PoolKey = (PathBuf, Option<&'static str>);
+
+/// Idle exiftool processes of one path and config. A process is checked out for each command,
+/// so parallel callers each get their own process, up to `max` processes.
+#[derive(Debug)]
+struct Pool {
+    key: PoolKey,
+    max: usize,
+    state: Mutex<PoolState>,
+    /// Notified when a process is checked in, or a slot is freed
+    available: Condvar,
+    /// The `-config` file, deleted with the pool once the processes have exited
+    config: Option<tempfile::NamedTempFile>,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    idle: Vec<Process>,
+    /// Idle and checked out processes
+    live: usize,
+}
+
+impl Pool {
+    /// The pool of the key if an `ExifTool` still uses it, else a new one
+    fn get(key: PoolKey) -> anyhow::Result<Arc<Pool>> {
+        static POOLS: OnceLock<Mutex<HashMap<PoolKey, Weak<Pool>>>> = OnceLock::new();
+
+        let mut pools = POOLS.get_or_init(Default::default).lock().unwrap();
+        if let Some(pool) = pools.get(&key).and_then(Weak::upgrade) {
+            return Ok(pool);
+        }
+        pools.retain(|_, pool| pool.strong_count() > 0);
+        let config = match key.1 {
+            Some(content) => {
+                let mut file = tempfile::NamedTempFile::new()?;
+                file.write_all(content.as_bytes())?;
+                file.flush()?;
+                Some(file)
+            }
+            None => None,
+        };
+        let pool = Arc::new(Pool {
+            key: key.clone(),
+            // one per conversion thread
+            max: rayon::current_num_threads().max(1),
+            state: Default::default(),
+            available: Condvar::new(),
+            config,
+        });
+        pools.insert(key, Arc::downgrade(&pool));
+        Ok(pool)
+    }
+
+    fn execute(&self, args: &[OsString]) -> anyhow::Result<ExifToolOutput> {
+        let idle = self.check_out();
+        let result = (|| {
+            let mut process = match idle {
+                Some(mut process) => match process.is_alive() {
+                    true => process,
+                    false => {
+                        // exited since its last command
+                        debug!("exiftool process exited, restarting");
+                        self.spawn()?
+                    }
+                },
+                None => self.spawn()?,
+            };
+            let output = process.execute(args)?;
+            anyhow::Ok((process, output))
+        })();
+        // a failed process is dropped (and stopped), a new one is spawned for the next command
+        match result {
+            Ok((process, output)) => {
+                self.check_in(Some(process));
+                Ok(output)
+            }
+            Err(e) => {
+                self.check_in(None);
+                Err(e)
+            }
+        }
+    }
+
+    /// Take an idle process, or a slot to spawn one (None), waiting while `max` processes are busy
+    fn check_out(&self) -> Option<Process> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(process) = state.idle.pop() {
+                return Some(process);
+            }
+            if state.live < self.max {
+                state.live += 1;
+                return None;
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    /// Return a checked out process, or free its slot
+    fn check_in(&self, process: Option<Process>) {
+        let mut state = self.state.lock().unwrap();
+        match process {
+            Some(process) => state.idle.push(process),
+            None => state.live -= 1,
+        }
+        self.available.notify_one();
+    }
+
+    fn spawn(&self) -> anyhow::Result<Process> {
+        let mut cmd = std::process::Command::new(&self.key.0);
+        if let Some(config) = self.config.as_ref() {
+            // must be the first argument
+            cmd.arg("-config").arg(config.path());
+        }
+        cmd.args(["-stay_open", "True", "-@", "-"]);
+        if cfg!(windows) {
+            cmd.args(["-common_args", "-charset", "filename=utf8"]);
+        }
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => anyhow::Error::new(ExifToolNotFound {
+                    program: self.key.0.clone(),
+                }),
+                _ => anyhow::Error::new(e),
+            })
+            .context("Run exiftool command failed. Is exiftool path corrent?")?;
+        trace!(pid = child.id(), "exiftool process started");
+        Ok(Process {
+            stdin: Some(child.stdin.take().context("no exiftool stdin")?),
+            stdout: Process::lines(child.stdout.take().context("no exiftool stdout")?),
+            stderr: Process::lines(child.stderr.take().context("no exiftool stderr")?),
+            child,
+            last_id: 0,
+        })
+    }
+}
+
+/// An `exiftool -stay_open True -@ -` process. Its output is read line by line on background threads.
+#[derive(Debug)]
+struct Process {
+    child: Child,
+    /// Taken when the process is stopped
+    stdin: Option<ChildStdin>,
+    stdout: Receiver<String>,
+    stderr: Receiver<String>,
+    last_id: u64,
+}
+
+impl Process {
+    fn lines(reader: impl Read + Send + 'static) -> Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut line = vec![];
+            while matches!(reader.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+                if tx.send(String::from_utf8_lossy(&line).trim_end().to_string()).is_err() {
+                    break;
+                }
+                line.clear();
+            }
+        });
+        rx
+    }
+
+    fn is_alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    fn execute(&mut self, args: &[OsString]) -> anyhow::Result<ExifToolOutput> {
+        self.last_id += 1;
+        let id = self.last_id;
+        let mut request = vec![];
+        for arg in args {
+            // the argfile is read as bytes, so paths need not be UTF-8 (on Windows, they are read as UTF-8)
+            let arg = arg.as_encoded_bytes();
+            anyhow::ensure!(
+                !arg.contains(&b'\n'),
+                "exiftool argument contains a new line: {:?}",
+                String::from_utf8_lossy(arg)
+            );
+            request.extend_from_slice(arg);
+            request.push(b'\n');
+        }
+        // -execute{id} ends stdout with "{ready{id}}", and -echo4 does the same for stderr
+        request.extend_from_slice(format!("-echo4\n{{ready{id}}}\n-execute{id}\n").as_bytes());
+        let stdin = self.stdin.as_mut().context("exiftool stopped")?;
+        stdin.write_all(&request).context("write to exiftool failed")?;
+        stdin.flush().context("write to exiftool failed")?;
+
+        let deadline = Instant::now() + TIMEOUT;
+        let marker = format!("{{ready{id}}}");
+        Ok(ExifToolOutput {
+            stdout: Self::read_until(&self.stdout, &marker, deadline)?,
+            stderr: Self::read_until(&self.stderr, &marker, deadline)?,
+        })
+    }
+
+    fn read_until(lines: &Receiver<String>, marker: &str, deadline: Instant) -> anyhow::Result<String> {
+        let mut output = String::new();
+        loop {
+            match lines.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(line) if line == marker => return Ok(output),
+                Ok(line) => {
+                    output.push_str(&line);
+                    output.push('\n');
+                }
+                Err(RecvTimeoutError::Timeout) => bail!("exiftool timed out after {TIMEOUT:?}"),
+                Err(RecvTimeoutError::Disconnected) => bail!("exiftool exited unexpectedly"),
+            }
+        }
+    }
+}
+
+/// How long a stopped process has to exit before it is killed
+const EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl Drop for Process {
+    /// Ask the process to exit, and close its input. It is killed if it does not exit in time, e.g. after a timeout.
+    fn drop(&mut self) {
+        if let Some(mut stdin) = self.stdin.take() {
+            stdin.write_all(b"-stay_open\nFalse\n").and_then(|()| stdin.flush()).ok();
+        }
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        while matches!(self.child.try_wait(), Ok(None)) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        if matches!(self.child.try_wait(), Ok(None)) {
+            self.child.kill().ok();
+        }
+        self.child.wait().ok();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::{ffi::OsStrExt, fs::PermissionsExt};
+
+    /// Answers like `exiftool -stay_open True -@ -`, printing the arguments of each command.
+    /// Logs "stopped" next to itself when asked to exit.
+    const FAKE_EXIFTOOL: &str = r#"#!/bin/sh
+while IFS= read -r line; do
+    case "$line" in
+        -echo4) read -r marker ;;
+        -execute*) echo "$marker"; echo "$marker" >&2 ;;
+        -stay_open) read -r value; [ "$value" = False ] && echo stopped >> "$0.log" && exit 0 ;;
+        *) printf '%s\n' "$line" ;;
+    esac
+done
+"#;
+
+    fn fake_exiftool(dir: &Path) -> PathBuf {
+        let program = dir.join("exiftool");
+        std::fs::write(&program, FAKE_EXIFTOOL).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        program
+    }
+
+    fn pid(pool: &Pool) -> u32 {
+        pool.state.lock().unwrap().idle.last().unwrap().child.id()
+    }
+
+    #[test]
+    fn restart_killed_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let exif_tool = ExifTool::with_path(fake_exiftool(dir.path()));
+
+        // a path that is not UTF-8 is passed as is
+        let file = OsStr::from_bytes(b"photo-\xff.jpg");
+        let output = exif_tool.execute([OsStr::new("-json"), file]).unwrap();
+        assert_eq!(output.stdout.as_bytes(), b"-json\nphoto-\xEF\xBF\xBD.jpg\n");
+        let pool = exif_tool.pool(None).unwrap();
+        let first = pid(&pool);
+
+        // the idle process is reused
+        exif_tool.execute(["-ver"]).unwrap();
+        assert_eq!(pid(&pool), first);
+
+        // killed between commands, the next command starts a new one
+        {
+            let mut state = pool.state.lock().unwrap();
+            let process = state.idle.last_mut().unwrap();
+            process.child.kill().unwrap();
+            process.child.wait().unwrap();
+        }
+        let output = exif_tool.execute(["-ver"]).unwrap();
+        assert_eq!(output.stdout, "-ver\n");
+        assert_ne!(pid(&pool), first);
+    }
+
+    #[test]
+    fn bounded_and_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let program = fake_exiftool(dir.path());
+        let exif_tool = ExifTool::with_path(&program).with_config("# no tags");
+        let pool = exif_tool.pool(exif_tool.config).unwrap();
+        let config = pool.config.as_ref().unwrap().path().to_path_buf();
+
+        // more callers than processes wait for one
+        std::thread::scope(|scope| {
+            for _ in 0..pool.max * 2 {
+                scope.spawn(|| {
+                    for _ in 0..5 {
+                        exif_tool.execute(["-ver"]).unwrap();
+                    }
+                });
+            }
+        });
+        let live = {
+            let state = pool.state.lock().unwrap();
+            assert_eq!(state.idle.len(), state.live);
+            state.live
+        };
+        assert!((1..=pool.max).contains(&live), "{live} processes");
+
+        // dropping the last user stops the processes, and deletes the config
+        drop(pool);
+        drop(exif_tool);
+        let log = std::fs::read_to_string(dir.path().join("exiftool.log")).unwrap();
+        assert_eq!(log.lines().filter(|line| *line == "stopped").count(), live);
+        assert!(!config.exists());
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// Reads and writes the metadata of the converted photos
+pub trait MetadataBackend: Send + Sync + std::fmt::Debug {
+    fn read(&self, file: &Path) -> Result<PhotoMetadata>;
+    /// Copy all tags of an image to another, except the orientation
+    fn copy_tags(&self, src: &Path, dst: &Path) -> Result<()>;
+    /// Copy capture time, location and camera of a video to an image
+    fn copy_video_tags(&self, src: &Path, dst: &Path) -> Result<()>;
+    /// Set the image size tags (`ExifImageWidth`, `ExifImageHeight`), e.g. after copying the tags of an image of another size
+    fn set_image_size(&self, file: &Path, width: usize, height: usize) -> Result<()>;
+    /// Mark an image as a motion photo, whose last `video_size` bytes are the video with the key photo at `presentation_timestamp_us`
+    fn write_motion_photo(&self, file: &Path, video_size: u64, presentation_timestamp_us: i64) -> Result<()>;
+    /// Add `seconds` to the capture dates of an image, and set their time zone offsets ("+HH:MM") if given
+    fn shift_dates(&self, file: &Path, seconds: i64, offset: Option<&str>) -> Result<()>;
+    /// Remove the tags of an image that the policy does not keep
+    fn apply_policy(&self, file: &Path, policy: MetadataPolicy) -> Result<()>;
+    fn is_motion_photo(&self, file: &Path) -> Result<bool> {
+        Ok(self.read(file)?.micro_video.is_some())
+    }
+}
+
+/// Which metadata of the sources is kept in the motion photo, in the image and in the video alike
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MetadataPolicy {
+    #[default]
+    KeepAll,
+    /// Remove GPS and place names
+    StripLocation,
+    /// Remove MakerNotes and serial numbers
+    StripDevice,
+    /// Only keep the capture date, and what is needed to show the photo (color profile, HDR gain map)
+    DateOnly,
+}
+
+impl MetadataPolicy {
+    /// Whether a video metadata key, e.g. `com.apple.quicktime.location.ISO6709`, is kept
+    pub fn keeps_video_key(&self, key: &str) -> bool {
+        let key = key.to_ascii_lowercase();
+        match self {
+            Self::KeepAll => true,
+            Self::StripLocation => !key.contains("location"),
+            Self::StripDevice => !key.contains("serial"),
+            Self::DateOnly => key == "creation_time" || key.ends_with("creationdate"),
+        }
+    }
+}
+
+/// The tags of a photo that the conversion reads, as exiftool `-json -n` numbers and strings
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PhotoMetadata {
+    /// XMP tag of Apple HDR photos (HDR gain map version 1.0 and later)
+    #[serde(rename = "HDRGainMapVersion")]
+    pub hdr_gain_map_version: Option<i64>,
+    #[serde(rename = "HDRGainMapHeadroom")]
+    pub hdr_gain_map_headroom: Option<f32>,
+    /// Apple maker note "marker 33", for photos without HDRGainMapHeadroom
+    #[serde(rename = "HDRHeadroom")]
+    pub hdr_headroom: Option<f32>,
+    /// Apple maker note "marker 48", for photos without HDRGainMapHeadroom
+    #[serde(rename = "HDRGain")]
+    pub hdr_gain: Option<f32>,
+    /// ICC profile, e.g. "Display P3"
+    pub profile_description: Option<String>,
+    pub exif_image_width: Option<usize>,
+    pub exif_image_height: Option<usize>,
+    /// "YYYY:mm:dd HH:MM:SS", local time
+    pub date_time_original: Option<String>,
+    /// "+HH:MM"
+    pub offset_time_original: Option<String>,
+    /// Local time for EXIF, UTC for QuickTime
+    pub create_date: Option<String>,
+    pub modify_date: Option<String>,
+    /// QuickTime creation date, with the time zone offset
+    pub creation_date: Option<String>,
+    #[serde(rename = "GPSLatitude")]
+    pub gps_latitude: Option<f64>,
+    #[serde(rename = "GPSLongitude")]
+    pub gps_longitude: Option<f64>,
+    #[serde(rename = "GPSAltitude")]
+    pub gps_altitude: Option<f64>,
+    /// Set if the photo is a Google motion photo
+    pub micro_video: Option<i64>,
+    pub micro_video_offset: Option<u64>,
+    pub micro_video_presentation_timestamp_us: Option<i64>,
+}
+
+impl PhotoMetadata {
+    /// Tags to read for all fields
+    pub const TAGS: &'static [&'static str] = &[
+        "XMP:HDRGainMapVersion",
+        "XMP:HDRGainMapHeadroom",
+        "MakerNotes:HDRHeadroom",
+        "MakerNotes:HDRGain",
+        "ProfileDescription",
+        "ExifImageWidth",
+        "ExifImageHeight",
+        "DateTimeOriginal",
+        "OffsetTimeOriginal",
+        "CreateDate",
+        "ModifyDate",
+        "QuickTime:CreationDate",
+        // signed by the reference direction
+        "Composite:GPSLatitude",
+        "Composite:GPSLongitude",
+        "Composite:GPSAltitude",
+        "XMP-GCamera:MicroVideo",
+        "XMP-GCamera:MicroVideoOffset",
+        "XMP-GCamera:MicroVideoPresentationTimestampUs",
+    ];
+
+    /// Whether the photo has an Apple HDR gain map
+    pub fn is_apple_hdr(&self) -> bool {
+        self.hdr_gain_map_version.is_some()
+    }
+
+    /// Capture time with a known time zone: `DateTimeOriginal` + `OffsetTimeOriginal`, or the QuickTime `CreationDate`
+    pub fn capture_time(&self) -> Option<SystemTime> {
+        if let (Some(date), Some(offset)) = (self.date_time_original.as_deref(), self.offset_time_original.as_deref()) {
+            return super::time::parse_time(date, Some(offset));
+        }
+        super::time::parse_time(self.creation_date.as_deref()?, None)
+    }
+
+    /// The QuickTime `CreateDate` of a video, which is in UTC
+    pub fn quicktime_create_time(&self) -> Option<SystemTime> {
+        super::time::parse_time(self.create_date.as_deref()?, Some("Z"))
+    }
+}
+
+/// Metadata kept in memory instead of the files, for tests. Files start without any tag.
+#[derive(Debug, Default)]
+pub struct MockMetadataBackend {
+    files: Mutex<HashMap<PathBuf, PhotoMetadata>>,
+}
+
+impl MockMetadataBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn insert(&self, file: impl Into<PathBuf>, metadata: PhotoMetadata) {
+        self.files.lock().unwrap().insert(file.into(), metadata);
+    }
+    pub fn get(&self, file: impl AsRef<Path>) -> Option<PhotoMetadata> {
+        self.files.lock().unwrap().get(file.as_ref()).cloned()
+    }
+}
+
+impl MetadataBackend for MockMetadataBackend {
+    fn read(&self, file: &Path) -> Result<PhotoMetadata> {
+        anyhow::ensure!(file.is_file(), "File not found: {}", file.display());
+        Ok(self.get(file).unwrap_or_default())
+    }
+    fn copy_tags(&self, src: &Path, dst: &Path) -> Result<()> {
+        let metadata = self.read(src)?;
+        self.insert(dst, metadata);
+        Ok(())
+    }
+    fn copy_video_tags(&self, src: &Path, dst: &Path) -> Result<()> {
+        let video = self.read(src)?;
+        let mut metadata = self.read(dst)?;
+        // the local date and time of the CreationDate, without its time zone
+        if let Some(local) = video.creation_date.as_deref().and_then(|date| date.get(..19)) {
+            metadata.date_time_original = Some(local.to_string());
+            metadata.create_date = Some(local.to_string());
+        }
+        metadata.gps_latitude = video.gps_latitude;
+        metadata.gps_longitude = video.gps_longitude;
+        metadata.gps_altitude = video.gps_altitude;
+        self.insert(dst, metadata);
+        Ok(())
+    }
+    fn set_image_size(&self, file: &Path, width: usize, height: usize) -> Result<()> {
+        let mut metadata = self.read(file)?;
+        metadata.exif_image_width = Some(width);
+        metadata.exif_image_height = Some(height);
+        self.insert(file, metadata);
+        Ok(())
+    }
+    fn apply_policy(&self, file: &Path, policy: MetadataPolicy) -> Result<()> {
+        let metadata = self.read(file)?;
+        let metadata = match policy {
+            MetadataPolicy::KeepAll => metadata,
+            MetadataPolicy::StripLocation => PhotoMetadata {
+                gps_latitude: None,
+                gps_longitude: None,
+                gps_altitude: None,
+                ..metadata
+            },
+            MetadataPolicy::StripDevice => PhotoMetadata {
+                hdr_headroom: None,
+                hdr_gain: None,
+                ..metadata
+            },
+            MetadataPolicy::DateOnly => PhotoMetadata {
+                hdr_gain_map_version: metadata.hdr_gain_map_version,
+                hdr_gain_map_headroom: metadata.hdr_gain_map_headroom,
+                profile_description: metadata.profile_description,
+                date_time_original: metadata.date_time_original,
+                offset_time_original: metadata.offset_time_original,
+                create_date: metadata.create_date,
+                modify_date: metadata.modify_date,
+                creation_date: metadata.creation_date,
+                ..Default::default()
+            },
+        };
+        self.insert(file, metadata);
+        Ok(())
+    }
+    fn shift_dates(&self, file: &Path, seconds: i64, offset: Option<&str>) -> Result<()> {
+        let mut metadata = self.read(file)?;
+        for date in [
+            &mut metadata.date_time_original,
+            &mut metadata.create_date,
+            &mut metadata.modify_date,
+        ] {
+            if let Some(shifted) = date.as_deref().and_then(|date| super::time::shift_date(date, seconds)) {
+                *date = Some(shifted);
+            }
+        }
+        if let Some(offset) = offset {
+            metadata.offset_time_original = Some(offset.to_string());
+        }
+        self.insert(file, metadata);
+        Ok(())
+    }
+    fn write_motion_photo(&self, file: &Path, video_size: u64, presentation_timestamp_us: i64) -> Result<()> {
+        let mut metadata = self.read(file)?;
+        metadata.micro_video = Some(1);
+        metadata.micro_video_offset = Some(video_size);
+        metadata.micro_video_presentation_timestamp_us = Some(presentation_timestamp_us);
+        self.insert(file, metadata);
+        Ok(())
+    }
+}
@@ -1,2 +1,7 @@
 mod exiftool;
-pub use exiftool::ExifTool;
+mod metadata;
+mod time;
+pub use exiftool::{ExifTool, ExifToolNotFound};
+pub use metadata::{MetadataBackend, MetadataPolicy, MockMetadataBackend, PhotoMetadata};
+pub use time::TimeShift;
+pub(crate) use time::{date_offset, format_offset, parse_offset, parse_time};
@@ -0,0 +1,264 @@
+//! Dates as written in EXIF ("YYYY:mm:dd HH:MM:SS") and QuickTime ("YYYY-mm-ddTHH:MM:SSZ") metadata
+//!
+
+use std::time::{Duration, SystemTime};
+
+/// Correction of wrong capture dates, applied to the image and the video alike
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TimeShift {
+    /// The device clock was off: move all dates by this many seconds
+    Offset(i64),
+    /// The dates are right in UTC, but the photo was taken in this time zone (seconds east of UTC):
+    /// rewrite the local dates and their offsets
+    TimeZone(i64),
+}
+
+impl TimeShift {
+    /// Parse a time zone like "+09:00", "-0530" or "Z"
+    pub fn time_zone(offset: &str) -> Option<Self> {
+        parse_offset(offset).map(Self::TimeZone)
+    }
+
+    /// Shift an instant
+    pub fn apply(&self, time: SystemTime) -> SystemTime {
+        match *self {
+            Self::Offset(seconds) if seconds >= 0 => time + Duration::from_secs(seconds as u64),
+            Self::Offset(seconds) => time - Duration::from_secs(seconds.unsigned_abs()),
+            Self::TimeZone(_) => time,
+        }
+    }
+
+    /// Seconds to add to local dates whose time zone offset is `offset` (seconds east of UTC).
+    /// None if the time zone is changed but `offset` is unknown.
+    pub fn local_seconds(&self, offset: Option<i64>) -> Option<i64> {
+        match *self {
+            Self::Offset(seconds) => Some(seconds),
+            Self::TimeZone(zone) => Some(zone - offset?),
+        }
+    }
+
+    /// Shift a date that has a time zone, keeping its format. UTC dates stay in UTC.
+    pub(crate) fn shift_date(&self, date: &str) -> Option<String> {
+        let mut date = DateTime::parse(date)?;
+        match (*self, date.zone) {
+            (_, Zone::Local) => return None,
+            (Self::Offset(seconds), _) => date.local_s += seconds,
+            (Self::TimeZone(_), Zone::Utc) => {}
+            (Self::TimeZone(zone), Zone::Offset { seconds, colon }) => {
+                date.local_s += zone - seconds;
+                date.zone = Zone::Offset { seconds: zone, colon };
+            }
+        }
+        Some(date.format())
+    }
+}
+
+/// Move a date by some seconds, keeping its format and time zone
+pub(crate) fn shift_date(date: &str, seconds: i64) -> Option<String> {
+    let mut date = DateTime::parse(date)?;
+    date.local_s += seconds;
+    Some(date.format())
+}
+
+/// Parse a date. `offset` ("+HH:MM" or "Z") is used if the date has no zone.
+pub(crate) fn parse_time(date: &str, offset: Option<&str>) -> Option<SystemTime> {
+    let date = DateTime::parse(date)?;
+    let offset_s = match date.zone {
+        Zone::Local => parse_offset(offset?)?,
+        Zone::Utc => 0,
+        Zone::Offset { seconds, .. } => seconds,
+    };
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(u64::try_from(date.local_s - offset_s).ok()?))
+}
+
+/// The time zone offset of a local date, in seconds east of UTC. None for a date without one, or in UTC.
+pub(crate) fn date_offset(date: &str) -> Option<i64> {
+    match DateTime::parse(date)?.zone {
+        Zone::Offset { seconds, .. } => Some(seconds),
+        Zone::Local | Zone::Utc => None,
+    }
+}
+
+/// "+HH:MM", "-HHMM" or "Z", in seconds east of UTC
+pub(crate) fn parse_offset(offset: &str) -> Option<i64> {
+    if offset == "Z" {
+        return Some(0);
+    }
+    let sign = match offset.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let offset = offset[1..].replace(':', "");
+    let (hours, minutes) = offset.split_at_checked(2)?;
+    Some(sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60))
+}
+
+/// "+HH:MM" (or "+HHMM" without colon)
+pub(crate) fn format_offset(seconds: i64, colon: bool) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.unsigned_abs() / 60;
+    match colon {
+        true => format!("{sign}{:02}:{:02}", minutes / 60, minutes % 60),
+        false => format!("{sign}{:02}{:02}", minutes / 60, minutes % 60),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Zone {
+    Local,
+    Utc,
+    Offset { seconds: i64, colon: bool },
+}
+
+#[derive(Debug)]
+struct DateTime<'a> {
+    /// The wall clock time, in seconds since 1970 as if it was UTC
+    local_s: i64,
+    /// e.g. ".123", or empty
+    sub_second: &'a str,
+    zone: Zone,
+    /// QuickTime format instead of EXIF
+    iso: bool,
+}
+
+impl<'a> DateTime<'a> {
+    fn parse(date: &'a str) -> Option<Self> {
+        let date = date.trim();
+        let iso = date.contains('T');
+        let (date, time) = date.split_once(if iso { 'T' } else { ' ' })?;
+        let [year, month, day] = parse_fields(date, if iso { '-' } else { ':' })?;
+        let (time, zone) = time.split_at(time.find(['+', '-', 'Z']).unwrap_or(time.len()));
+        let (time, sub_second) = time.split_at(time.find('.').unwrap_or(time.len()));
+        let [hour, minute, second] = parse_fields(time, ':')?;
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+        let zone = match zone {
+            "" => Zone::Local,
+            "Z" => Zone::Utc,
+            offset => Zone::Offset {
+                seconds: parse_offset(offset)?,
+                colon: offset.contains(':'),
+            },
+        };
+        Some(Self {
+            local_s: days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second,
+            sub_second,
+            zone,
+            iso,
+        })
+    }
+
+    fn format(&self) -> String {
+        let (days, seconds) = (self.local_s.div_euclid(86400), self.local_s.rem_euclid(86400));
+        let (year, month, day) = civil_from_days(days);
+        let (hour, minute, second) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+        let zone = match self.zone {
+            Zone::Local => String::new(),
+            Zone::Utc => "Z".to_string(),
+            Zone::Offset { seconds, colon } => format_offset(seconds, colon),
+        };
+        let sub_second = self.sub_second;
+        match self.iso {
+            true => format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}{sub_second}{zone}"),
+            false => format!("{year:04}:{month:02}:{day:02} {hour:02}:{minute:02}:{second:02}{sub_second}{zone}"),
+        }
+    }
+}
+
+fn parse_fields(s: &str, separator: char) -> Option<[i64; 3]> {
+    let mut fields = s.split(separator).map(|field| field.parse::<i64>().ok());
+    let parsed = [fields.next()??, fields.next()??, fields.next()??];
+    fields.next().is_none().then_some(parsed)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date, as per <https://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Inverse of [`days_from_civil`], as per <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shift_date_rollover() {
+        // day, month and year
+        assert_eq!(shift_date("2024:12:31 23:30:00", 3600).unwrap(), "2025:01:01 00:30:00");
+        assert_eq!(shift_date("2024:01:31 23:00:00", 3600).unwrap(), "2024:02:01 00:00:00");
+        assert_eq!(shift_date("2025:01:01 00:30:00", -3600).unwrap(), "2024:12:31 23:30:00");
+        // leap day
+        assert_eq!(shift_date("2024:02:28 23:00:00", 3600).unwrap(), "2024:02:29 00:00:00");
+        assert_eq!(shift_date("2024:03:01 00:30:00", -3600).unwrap(), "2024:02:29 23:30:00");
+        assert_eq!(shift_date("2023:02:28 23:00:00", 3600).unwrap(), "2023:03:01 00:00:00");
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(parse_offset("+0900"), Some(32400));
+        assert_eq!(parse_offset("+09:00"), Some(32400));
+        assert_eq!(parse_offset("-05:30"), Some(-19800));
+        assert_eq!(parse_offset("Z"), Some(0));
+        assert_eq!(parse_offset("0900"), None);
+        assert_eq!(format_offset(-19800, true), "-05:30");
+        assert_eq!(format_offset(32400, false), "+0900");
+
+        let utc = parse_time("2024-01-01T00:00:00Z", None).unwrap();
+        assert_eq!(utc, SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_067_200));
+        assert_eq!(parse_time("2024-01-01T09:00:00+0900", None), Some(utc));
+        assert_eq!(parse_time("2024:01:01 09:00:00", Some("+09:00")), Some(utc));
+        assert_eq!(parse_time("2024:01:01 09:00:00", None), None);
+
+        assert_eq!(date_offset("2024-01-01T09:00:00+0900"), Some(32400));
+        assert_eq!(date_offset("2024-01-01T00:00:00Z"), None);
+        assert_eq!(date_offset("2024:01:01 09:00:00"), None);
+    }
+
+    #[test]
+    fn sub_second_kept() {
+        assert_eq!(shift_date("2024:01:01 23:59:30.123", 60).unwrap(), "2024:01:02 00:00:30.123");
+        let shift = TimeShift::Offset(60);
+        assert_eq!(
+            shift.shift_date("2024-01-01T00:00:00.000000Z").unwrap(),
+            "2024-01-01T00:01:00.000000Z"
+        );
+    }
+
+    #[test]
+    fn time_zone() {
+        let shift = TimeShift::TimeZone(32400);
+        // UTC dates are right already
+        assert_eq!(
+            shift.shift_date("2024-01-01T00:00:00.000000Z").unwrap(),
+            "2024-01-01T00:00:00.000000Z"
+        );
+        // local dates move to the new zone, keeping the offset format
+        assert_eq!(shift.shift_date("2024-01-01T01:00:00+0100").unwrap(), "2024-01-01T09:00:00+0900");
+        assert_eq!(shift.shift_date("2024-01-01T01:00:00+01:00").unwrap(), "2024-01-01T09:00:00+09:00");
+        // without a zone, there is nothing to move from
+        assert_eq!(shift.shift_date("2024:01:01 01:00:00"), None);
+        assert_eq!(shift.local_seconds(Some(3600)), Some(28800));
+        assert_eq!(shift.local_seconds(None), None);
+    }
+}
@@ -0,0 +1,66 @@
+use aa_photo_bridge::{
+    i2a::{
+        video::{AudioOptions, KeyFrame, RateControl, VideoEncodeOptions},
+        ConvertError, ConvertRequest, ConvertRequestBuilder,
+    },
+    utils::MockMetadataBackend,
+};
+use std::sync::Arc;
+
+fn builder() -> ConvertRequestBuilder {
+    ConvertRequest::builder()
+        .output_path("./testoutput/builder.jpg")
+        .metadata(Arc::new(MockMetadataBackend::new()))
+}
+
+fn is_invalid(builder: ConvertRequestBuilder) -> bool {
+    matches!(builder.build(), Err(ConvertError::InvalidRequest { .. }))
+}
+
+fn video_encode(f: impl FnOnce(&mut VideoEncodeOptions)) -> VideoEncodeOptions {
+    let mut options = VideoEncodeOptions::default();
+    f(&mut options);
+    options
+}
+
+fn audio(f: impl FnOnce(&mut AudioOptions)) -> AudioOptions {
+    let mut options = AudioOptions::default();
+    f(&mut options);
+    options
+}
+
+#[test]
+fn main() {
+    let request = builder().image_quality(80).exact_trim(true).build().unwrap();
+    assert_eq!(request.output_path(), std::path::Path::new("./testoutput/builder.jpg"));
+    assert_eq!(request.image_path(), None);
+    assert_eq!(request.image_quality(), 80);
+    assert!(request.exact_trim());
+
+    assert!(matches!(
+        ConvertRequest::builder().build(),
+        Err(ConvertError::MissingOption { option: "output_path" })
+    ));
+
+    assert!(is_invalid(builder().image_quality(101)));
+    assert!(is_invalid(builder().gainmap_quality(-1)));
+
+    assert!(is_invalid(builder().video_encode(video_encode(|o| o.max_size = Some(0)))));
+    assert!(is_invalid(
+        builder().video_encode(video_encode(|o| o.rate_control = RateControl::Crf(52)))
+    ));
+    assert!(is_invalid(
+        builder().video_encode(video_encode(|o| o.rate_control = RateControl::BitRate(0)))
+    ));
+    assert!(!is_invalid(builder().video_encode(video_encode(|o| {
+        o.max_size = Some(2);
+        o.rate_control = RateControl::Crf(51);
+    }))));
+
+    assert!(is_invalid(builder().audio(audio(|o| o.bit_rate = -1))));
+    assert!(is_invalid(builder().audio(audio(|o| o.sample_rate = Some(0)))));
+    assert!(!is_invalid(builder().audio(audio(|o| o.sample_rate = Some(48000)))));
+
+    assert!(is_invalid(builder().key_frame(KeyFrame::Timestamp(-1))));
+    assert!(!is_invalid(builder().key_frame(KeyFrame::Timestamp(0))));
+}
@@ -0,0 +1,68 @@
+use aa_photo_bridge::{
+    i2a::{
+        progress::{CancellationToken, Progress, ProgressObserver, Stage},
+        ConvertError, ConvertRequest,
+    },
+    utils::MockMetadataBackend,
+};
+use std::{path::Path, sync::Arc};
+
+/// Cancels the conversion when it reaches a stage
+struct CancelAt(Stage, CancellationToken);
+
+impl ProgressObserver for CancelAt {
+    fn stage(&self, stage: Stage) {
+        if stage == self.0 {
+            self.1.cancel();
+        }
+    }
+}
+
+fn convert(output: &Path, progress: Progress) -> Result<(), ConvertError> {
+    ConvertRequest::builder()
+        .image_path("./tests/IMG_3853.HEIC")
+        .video_path("./tests/IMG_3853.MOV")
+        .output_path(output)
+        .metadata(Arc::new(MockMetadataBackend::new()))
+        .overwrite_existing(true)
+        .progress(progress)
+        .build()
+        .unwrap()
+        .convert()
+        .map(|_| ())
+}
+
+#[test]
+fn main() {
+    tracing_subscriber::fmt::fmt().with_max_level(tracing::Level::DEBUG).init();
+    std::fs::create_dir_all("./testoutput").unwrap();
+
+    // cancelled before the start: no output
+    let output = Path::new("./testoutput/MVIMG_3853-cancelled.jpg");
+    if output.exists() {
+        std::fs::remove_file(output).unwrap();
+    }
+    let cancellation = CancellationToken::new();
+    cancellation.cancel();
+    let progress = Progress {
+        observer: None,
+        cancellation: cancellation.clone(),
+    };
+    assert!(matches!(convert(output, progress.clone()), Err(ConvertError::Cancelled)));
+    assert!(!output.exists());
+
+    // an existing output is kept when cancelled before it is replaced
+    let existing = Path::new("./testoutput/MVIMG_3853-existing.jpg");
+    std::fs::write(existing, b"existing").unwrap();
+    assert!(matches!(convert(existing, progress), Err(ConvertError::Cancelled)));
+    assert_eq!(std::fs::read(existing).unwrap(), b"existing");
+
+    // cancelled after the output is written: the output is removed
+    let cancellation = CancellationToken::new();
+    let progress = Progress {
+        observer: Some(Arc::new(CancelAt(Stage::Video, cancellation.clone()))),
+        cancellation,
+    };
+    assert!(matches!(convert(output, progress), Err(ConvertError::Cancelled)));
+    assert!(!output.exists());
+}
@@ -1,13 +1,23 @@
 #[test]
 fn main() {
     tracing_subscriber::fmt::init();
-    // aa_photo_bridge::i2a::video::VideoAudioEncodeRequest::mute_ffmpeg_log();
-    aa_photo_bridge::i2a::video::VideoAudioEncodeRequest {
-        input: "./tests/IMG_3853.MOV".as_ref(),
-        // input: "./tests/IMG_3281.MOV".as_ref(),
-        output: "./testoutput/IMG_3853-aac.mp4".as_ref(),
-        encoder: "aac",
-        bit_rate: 128_000,
+    let mut audio = aa_photo_bridge::i2a::video::AudioOptions::default();
+    audio.bit_rate = 128_000;
+    audio.passthrough_codecs = vec![];
+    // aa_photo_bridge::i2a::video::VideoRemuxRequest::mute_ffmpeg_log();
+    aa_photo_bridge::i2a::video::VideoRemuxRequest {
+        input: std::path::Path::new("./tests/IMG_3853.MOV").into(),
+        // input: std::path::Path::new("./tests/IMG_3281.MOV").into(),
+        output: std::path::Path::new("./testoutput/IMG_3853-aac.mp4").into(),
+        trim: None,
+        exact_trim: false,
+        video: aa_photo_bridge::i2a::video::StreamAction::Copy,
+        audio: aa_photo_bridge::i2a::video::StreamAction::Transcode(audio),
+        rotation: Default::default(),
+        hdr: Default::default(),
+        progress: Default::default(),
+        metadata: Default::default(),
+        time_shift: None,
     }
     .execute()
     .unwrap();
@@ -0,0 +1,20 @@
+use aa_photo_bridge::utils::MockMetadataBackend;
+use std::sync::Arc;
+
+#[test]
+fn main() {
+    tracing_subscriber::fmt::fmt().with_max_level(tracing::Level::DEBUG).init();
+
+    let image: Arc<[u8]> = std::fs::read("./tests/IMG_3853.HEIC").unwrap().into();
+    let video: Arc<[u8]> = std::fs::read("./tests/IMG_3853.MOV").unwrap().into();
+    let output = aa_photo_bridge::i2a::ConvertRequest::builder()
+        .metadata(Arc::new(MockMetadataBackend::new()))
+        .convert_bytes(image.clone(), video.clone())
+        .unwrap();
+
+    // the jpg, followed by the video
+    assert!(output.starts_with(&[0xFF, 0xD8]));
+    assert!(output.len() > image.len().min(video.len()));
+    // the video is appended after the metadata is written
+    assert!(output.ends_with(&video));
+}
@@ -0,0 +1,43 @@
+use aa_photo_bridge::{
+    i2a::video::KeyFrame,
+    utils::{MetadataBackend, MockMetadataBackend, PhotoMetadata},
+};
+use std::{path::PathBuf, sync::Arc};
+
+#[test]
+fn main() {
+    tracing_subscriber::fmt::fmt().with_max_level(tracing::Level::DEBUG).init();
+
+    let output = PathBuf::from("./testoutput/MVIMG_3853-frame.jpg");
+    if output.exists() {
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    let metadata = Arc::new(MockMetadataBackend::new());
+    let image = PathBuf::from("./tests/IMG_3853.HEIC");
+    metadata.insert(
+        &image,
+        PhotoMetadata {
+            exif_image_width: Some(4032),
+            exif_image_height: Some(3024),
+            ..Default::default()
+        },
+    );
+    aa_photo_bridge::i2a::ConvertRequest::builder()
+        .image_path(&image)
+        .video_path("./tests/IMG_3853.MOV")
+        .output_path(&output)
+        .metadata(metadata.clone())
+        .key_frame(KeyFrame::Index(0))
+        .build()
+        .unwrap()
+        .convert()
+        .unwrap();
+
+    assert!(metadata.is_motion_photo(&output).unwrap());
+    // the size tags copied from the image are replaced by the size of the frame
+    let tags = metadata.get(&output).unwrap();
+    let (width, height) = (tags.exif_image_width.unwrap(), tags.exif_image_height.unwrap());
+    let header = turbojpeg::read_header(&std::fs::read(&output).unwrap()).unwrap();
+    assert_eq!((header.width, header.height), (width, height));
+}
@@ -0,0 +1,25 @@
+use aa_photo_bridge::utils::{MetadataBackend, MockMetadataBackend};
+use std::{path::PathBuf, sync::Arc};
+
+#[test]
+fn main() {
+    tracing_subscriber::fmt::fmt().with_max_level(tracing::Level::DEBUG).init();
+
+    let output = PathBuf::from("./testoutput/IMG_3853-uhdr.jpg");
+    if output.exists() {
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    let metadata = Arc::new(MockMetadataBackend::new());
+    let report = aa_photo_bridge::i2a::ConvertRequest::builder()
+        .image_path("./tests/IMG_3853.HEIC")
+        .output_path(&output)
+        .metadata(metadata.clone())
+        .build()
+        .unwrap()
+        .convert_image()
+        .unwrap();
+
+    assert!(!metadata.is_motion_photo(&output).unwrap());
+    assert_eq!(report.video_offset, 0);
+}
@@ -1,4 +1,8 @@
-use std::path::PathBuf;
+use aa_photo_bridge::{
+    i2a::progress::Stage,
+    utils::{MetadataBackend, MockMetadataBackend},
+};
+use std::{path::PathBuf, sync::Arc};
 
 #[test]
 fn main() {
@@ -9,14 +13,29 @@ fn main() {
         std::fs::remove_file(&output).unwrap();
     }
 
-    aa_photo_bridge::i2a::ConvertRequest {
-        image_path: "./tests/IMG_3853.HEIC".into(),
-        video_path: "./tests/IMG_3853.MOV".into(),
-        output_path: output,
-        exiftool_path: Some(r"c:\My Program Files\exiftool\exiftool.exe".into()),
-        image_quality: 85,
-        gainmap_quality: 85,
+    let metadata = Arc::new(MockMetadataBackend::new());
+    let report = aa_photo_bridge::i2a::ConvertRequest::builder()
+        .image_path("./tests/IMG_3853.HEIC")
+        .video_path("./tests/IMG_3853.MOV")
+        .output_path(&output)
+        .metadata(metadata.clone())
+        .build()
+        .unwrap()
+        .convert()
+        .unwrap();
+
+    assert!(metadata.is_motion_photo(&output).unwrap());
+    let written = metadata.get(&output).unwrap();
+    assert_eq!(report.video_offset, written.micro_video_offset.unwrap());
+    assert_eq!(
+        report.presentation_timestamp_us,
+        written.micro_video_presentation_timestamp_us.unwrap()
+    );
+    assert!(report.base_image_bytes > 0);
+    assert_eq!(report.hdr, report.headroom.is_some());
+    assert_eq!(report.hdr, report.gain_map_bytes.is_some());
+    let stages: Vec<_> = report.stages.iter().map(|duration| duration.stage).collect();
+    for stage in [Stage::Decode, Stage::Video, Stage::Metadata] {
+        assert!(stages.contains(&stage), "{stage:?} missing from {stages:?}");
     }
-    .convert()
-    .unwrap();
 }
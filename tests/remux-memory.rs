@@ -0,0 +1,64 @@
+use aa_photo_bridge::i2a::video::{StreamAction, VideoInput, VideoOutput, VideoRemuxRequest};
+use std::sync::Arc;
+
+/// The 4CC of the top-level boxes
+fn top_level_boxes(data: &[u8]) -> Vec<[u8; 4]> {
+    let mut boxes = vec![];
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let size = match u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize {
+            0 => data.len() - offset,
+            1 => u64::from_be_bytes(data[offset + 8..offset + 16].try_into().unwrap()) as usize,
+            size => size,
+        };
+        boxes.push(data[offset + 4..offset + 8].try_into().unwrap());
+        offset += size;
+    }
+    boxes
+}
+
+#[test]
+fn main() {
+    tracing_subscriber::fmt::init();
+    let input: Arc<[u8]> = std::fs::read("./tests/IMG_3853.MOV").unwrap().into();
+    let converted = VideoRemuxRequest {
+        input: VideoInput::Memory(&input),
+        output: VideoOutput::Memory,
+        trim: None,
+        exact_trim: false,
+        video: StreamAction::Copy,
+        audio: StreamAction::Copy,
+        rotation: Default::default(),
+        hdr: Default::default(),
+        progress: Default::default(),
+        metadata: Default::default(),
+        time_shift: None,
+    }
+    .execute()
+    .unwrap();
+
+    // faststart: moov is in front of the media data
+    let data: Arc<[u8]> = converted.data.unwrap().into();
+    let boxes = top_level_boxes(&data);
+    assert_eq!(&boxes[0], b"ftyp");
+    assert_eq!(&boxes[1], b"moov");
+    assert!(boxes.iter().any(|kind| kind == b"mdat"));
+
+    // and the moved chunk offsets still point to the media
+    let reconverted = VideoRemuxRequest {
+        input: VideoInput::Memory(&data),
+        output: VideoOutput::Memory,
+        trim: None,
+        exact_trim: false,
+        video: StreamAction::Copy,
+        audio: StreamAction::Copy,
+        rotation: Default::default(),
+        hdr: Default::default(),
+        progress: Default::default(),
+        metadata: Default::default(),
+        time_shift: None,
+    }
+    .execute()
+    .unwrap();
+    assert_eq!(reconverted.range, converted.range);
+}
@@ -0,0 +1,75 @@
+use aa_photo_bridge::{
+    i2a::{video::VideoUtils, ConvertError, ConvertRequest},
+    utils::{MockMetadataBackend, PhotoMetadata, TimeShift},
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const IMAGE: &str = "./tests/IMG_3853.HEIC";
+const VIDEO: &str = "./tests/IMG_3853.MOV";
+
+/// The image has a capture date, but no time zone offset
+fn image_without_offset() -> Arc<MockMetadataBackend> {
+    let metadata = Arc::new(MockMetadataBackend::new());
+    metadata.insert(
+        IMAGE,
+        PhotoMetadata {
+            date_time_original: Some("2024:01:01 12:00:00".to_string()),
+            ..Default::default()
+        },
+    );
+    metadata
+}
+
+fn remove(output: &Path) {
+    if output.exists() {
+        std::fs::remove_file(output).unwrap();
+    }
+}
+
+#[test]
+fn main() {
+    tracing_subscriber::fmt::fmt().with_max_level(tracing::Level::DEBUG).init();
+    std::fs::create_dir_all("./testoutput").unwrap();
+    let zone = TimeShift::time_zone("+09:00").unwrap();
+
+    // the image dates are taken to be in the time zone of the video, like the video dates
+    let output = PathBuf::from("./testoutput/MVIMG_3853-time-zone.jpg");
+    remove(&output);
+    let metadata = image_without_offset();
+    ConvertRequest::builder()
+        .image_path(IMAGE)
+        .video_path(VIDEO)
+        .output_path(&output)
+        .metadata(metadata.clone())
+        .time_shift(zone)
+        .build()
+        .unwrap()
+        .convert()
+        .unwrap();
+    let video_offset = VideoUtils::get_time_zone_offset(Path::new(VIDEO)).unwrap().unwrap();
+    let local_s = 12 * 3600 + 9 * 3600 - video_offset;
+    assert!((0..86400).contains(&local_s));
+    let tags = metadata.get(&output).unwrap();
+    assert_eq!(
+        tags.date_time_original.unwrap(),
+        format!("2024:01:01 {:02}:{:02}:00", local_s / 3600, local_s / 60 % 60)
+    );
+    assert_eq!(tags.offset_time_original.unwrap(), "+09:00");
+
+    // without a video, there is no time zone to change from: nothing is written
+    let output = PathBuf::from("./testoutput/IMG_3853-time-zone.jpg");
+    remove(&output);
+    let result = ConvertRequest::builder()
+        .image_path(IMAGE)
+        .output_path(&output)
+        .metadata(image_without_offset())
+        .time_shift(zone)
+        .build()
+        .unwrap()
+        .convert_image();
+    assert!(matches!(result, Err(ConvertError::InvalidRequest { .. })), "{result:?}");
+    assert!(!output.exists());
+}
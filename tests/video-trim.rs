@@ -0,0 +1,57 @@
+use aa_photo_bridge::i2a::video::{StreamAction, TimeRange, VideoInput, VideoOutput, VideoRemuxRequest, VideoTrim};
+use std::{sync::Arc, time::Duration};
+
+const DURATION_US: i64 = 3_000_000;
+
+fn range(start_us: i64, end_us: i64) -> TimeRange {
+    TimeRange { start_us, end_us }
+}
+
+#[test]
+fn main() {
+    let around = VideoTrim::Around {
+        before: Duration::from_secs(1),
+        after: Duration::from_millis(500),
+    };
+    assert_eq!(around.range(DURATION_US, 1_500_000), range(500_000, 2_000_000));
+    // clamped to the start and end of the video
+    assert_eq!(around.range(DURATION_US, 200_000), range(0, 700_000));
+    assert_eq!(around.range(DURATION_US, 2_800_000), range(1_800_000, DURATION_US));
+    // a key photo after the end is clamped to the end
+    assert_eq!(around.range(DURATION_US, 5_000_000), range(2_000_000, DURATION_US));
+
+    let max = VideoTrim::MaxDuration(Duration::from_secs(1));
+    assert_eq!(max.range(DURATION_US, 1_500_000), range(1_000_000, 2_000_000));
+    // shifted to stay inside the video
+    assert_eq!(max.range(DURATION_US, 200_000), range(0, 1_000_000));
+    assert_eq!(max.range(DURATION_US, 2_900_000), range(2_000_000, DURATION_US));
+    // longer than the clip keeps the whole clip
+    let longer = VideoTrim::MaxDuration(Duration::from_secs(10));
+    assert_eq!(longer.range(DURATION_US, 1_500_000), range(0, DURATION_US));
+}
+
+#[test]
+fn copy_from_keyframe() {
+    let input: Arc<[u8]> = std::fs::read("./tests/IMG_3853.MOV").unwrap().into();
+    // a microsecond after a whole second is not on a keyframe
+    let start_us = 1_000_001;
+    let converted = VideoRemuxRequest {
+        input: VideoInput::Memory(&input),
+        output: VideoOutput::Memory,
+        trim: Some(range(start_us, 2_500_000)),
+        exact_trim: false,
+        video: StreamAction::Copy,
+        audio: StreamAction::Copy,
+        rotation: Default::default(),
+        hdr: Default::default(),
+        progress: Default::default(),
+        metadata: Default::default(),
+        time_shift: None,
+    }
+    .execute()
+    .unwrap();
+    assert!(!converted.video_transcoded);
+    // starts at the keyframe before, which the presentation timestamp is relative to
+    assert!(converted.range.start_us < start_us, "{:?}", converted.range);
+    assert_eq!(converted.range.end_us, 2_500_000);
+}